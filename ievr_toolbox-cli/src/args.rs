@@ -3,18 +3,31 @@ use clap::{Parser, Subcommand};
 mod dump_args;
 mod decrypt_args;
 mod encrypt_args;
+mod info_args;
+mod extract_args;
+mod which_args;
+mod diff_args;
 
 pub use self::{
-    dump_args::DumpArgs,
+    dump_args::{DumpArgs, OutputFormat, ZipCompressionArg},
     decrypt_args::DecryptArgs,
     encrypt_args::EncryptArgs,
+    info_args::InfoArgs,
+    extract_args::ExtractArgs,
+    which_args::WhichArgs,
+    diff_args::DiffArgs,
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "IE VR Toolbox", long_about = None)]
 pub struct Args {
     #[command(subcommand)]
-    pub command: Command
+    pub command: Command,
+
+    /// Print one JSON object per line instead of text: the progress and the result of every
+    /// command, and its error. Each failure has its own exit code
+    #[arg(long, global = true)]
+    pub json: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -26,5 +39,18 @@ pub enum Command {
     Decrypt(DecryptArgs),
 
     /// Encrypt files into CRIware
-    Encrypt(EncryptArgs)
+    Encrypt(EncryptArgs),
+
+    /// Print the header and table statistics of a CPK archive
+    Info(InfoArgs),
+
+    /// Extract a single file from the game to a file or the standard output
+    #[command(alias = "cat")]
+    Extract(ExtractArgs),
+
+    /// Find which CPK contains a file using cpk_list.cfg.bin
+    Which(WhichArgs),
+
+    /// Compare the files of two versions of the game
+    Diff(DiffArgs),
 }
\ No newline at end of file
@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+pub struct DiffArgs {
+    /// The old version: a game folder, or a game index saved in the cache folder
+    #[arg(value_name = "OLD")]
+    pub old: String,
+
+    /// The new version: a game folder, or a game index saved in the cache folder
+    #[arg(value_name = "NEW")]
+    pub new: String,
+
+    /// Optional: A text file with rules restricting the files that are compared
+    #[arg(short, long, value_name = "RULES_FILE", default_value = "")]
+    pub rules_file: String,
+
+    /// Optional: only compare the TOCs, without hashing the content of the files.
+    /// Files modified without being resized are then not reported
+    #[arg(long)]
+    pub toc_only: bool,
+
+    /// Optional: a folder where both versions of every changed file are extracted,
+    /// in its "old" and "new" subfolders
+    #[arg(short, long, value_name = "OUT")]
+    pub output_folder: Option<PathBuf>,
+}
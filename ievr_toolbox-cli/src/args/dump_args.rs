@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 #[derive(Parser, Debug)]
 pub struct DumpArgs {
@@ -12,6 +12,17 @@ pub struct DumpArgs {
     #[arg(short, long, value_name = "OUT", default_value = "extracted")]
     pub output_folder: PathBuf,
 
+    /// Optional: How the extracted files are written: in the output folder, or in a single tar,
+    /// zip or blob file at the output path. Archives can't be resumed, dumped incrementally
+    /// or have a shadow folder
+    #[arg(long, value_name = "FORMAT", default_value = "folder")]
+    pub output_format: OutputFormat,
+
+    /// Optional: With --output-format zip, whether the files are stored as they are
+    /// or compressed with deflate, which is smaller but much slower
+    #[arg(long, value_name = "COMPRESSION", default_value = "store")]
+    pub zip_compression: ZipCompressionArg,
+
     /// Optional: the total amount of threads allocated to the program.
     /// A value of 0 will use all available threads
     #[arg(short, long, value_name = "THREADS", default_value = "0")]
@@ -22,8 +33,112 @@ pub struct DumpArgs {
     #[arg(short, long, value_name = "MEMORY", default_value = "0")]
     pub memory: f64,
 
+    /// Optional: The amount of disk space the CPKs decrypted to the temp folder
+    /// may use at once in GiB. A value of 0 will use the free space of the disk.
+    #[arg(short, long, value_name = "DISK", default_value = "0")]
+    pub disk: f64,
+
+    /// Optional: The amount of data the dump may read and write per second in MiB,
+    /// counting the decryption and the extraction together. A value of 0 won't limit it
+    #[arg(long, value_name = "MB_PER_SECOND", default_value = "0")]
+    pub max_io: f64,
+
+    /// Optional: Lower the CPU and disk priority of the dump, so that the computer stays
+    /// responsive while it runs. Only supported on Linux
+    #[arg(long)]
+    pub background: bool,
+
+    /// Optional: The amount of decrypted CPKs kept open at once, in RAM and in the temp
+    /// folder together. Once it is reached, the threads extract the waiting files before
+    /// decrypting more. A value of 0 will use twice the number of threads
+    #[arg(long, value_name = "CPKS", default_value = "0")]
+    pub max_open_cpks: usize,
+
+    /// Optional: The folder where big CPKs are decrypted before their files are extracted
+    #[arg(long, value_name = "TEMP", default_value = "temp")]
+    pub temp_folder: PathBuf,
+
+    /// Optional: The amount of decrypted CPKs kept in the temp folder after the dump
+    /// in GiB, so that the next dumps don't decrypt them again. Copies are checked against
+    /// their CPK before being reused and the least recently used are removed first.
+    /// A value of 0 removes every copy as soon as its files are extracted.
+    #[arg(long, value_name = "CACHE_SIZE", default_value = "0")]
+    pub cache_size: f64,
+
     /// Optional: A text file with regex rules for selecting files that need
     /// extracting
     #[arg(short, long, value_name = "RULES_FILE", default_value = "")]
     pub rules_file: String,
-}
\ No newline at end of file
+
+    /// Optional: A file where a record of every extracted file is written.
+    /// The format is JSON if the file ends with .json, CSV otherwise
+    #[arg(long, value_name = "MANIFEST")]
+    pub manifest: Option<PathBuf>,
+
+    /// Optional: Print the time each stage and each CPK took after the dump, with the throughputs
+    /// and how often the memory and disk budgets held the dump back
+    #[arg(long)]
+    pub stats: bool,
+
+    /// Optional: A JSON file where the same performance report as --stats is written
+    #[arg(long, value_name = "STATS_FILE")]
+    pub stats_file: Option<PathBuf>,
+
+    /// Optional: Resume an interrupted dump into the same output folder.
+    /// CPKs and files already extracted according to its journal are skipped
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Optional: Only extract the CPKs and files that changed since the previous
+    /// incremental dump into the same output folder
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// Optional: With --incremental, delete the extracted files that are no longer in the game
+    #[arg(long, requires = "incremental")]
+    pub remove_deleted: bool,
+
+    /// Optional: A text file listing CPK names, one per line, from the highest to the lowest
+    /// priority. It decides which copy of a file in several CPKs is extracted when
+    /// cpk_list.cfg.bin doesn't tell which one the game loads
+    #[arg(long, value_name = "PRIORITY_FILE")]
+    pub cpk_priority: Option<PathBuf>,
+
+    /// Optional: Print what would be extracted, the space it takes and the free space
+    /// of the output and temp disks, without extracting anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Optional: A folder where the copies of files overridden by another CPK are extracted,
+    /// in a subfolder named after their CPK. By default they are not extracted
+    #[arg(long, value_name = "SHADOW")]
+    pub shadow_folder: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Loose files in the output folder
+    Folder,
+    Tar,
+    Zip,
+    /// The files one after the other, followed by a JSON index
+    Blob,
+}
+
+impl OutputFormat {
+    /// Extension given to an archive whose output path has none
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Folder => None,
+            OutputFormat::Tar => Some("tar"),
+            OutputFormat::Zip => Some("zip"),
+            OutputFormat::Blob => Some("blob"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipCompressionArg {
+    Store,
+    Deflate,
+}
@@ -0,0 +1,19 @@
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+pub struct ExtractArgs {
+    /// Path to the game's folder containing CPK files
+    #[arg(short, long, value_name = "INPUT")]
+    pub input_folder: String,
+
+    /// Path of the file inside the game, for example
+    /// data/common/chara_param.cfg.bin
+    #[arg(short, long, value_name = "PATH")]
+    pub path: String,
+
+    /// Optional: the output path of the extracted file.
+    /// By default, or if set to "-", the file is written to the
+    /// standard output
+    #[arg(short, long, value_name = "OUT", default_value = "")]
+    pub output_file: String,
+}
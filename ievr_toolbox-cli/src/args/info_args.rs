@@ -0,0 +1,8 @@
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+pub struct InfoArgs {
+    /// Path to the CPK file to inspect
+    #[arg(short, long, value_name = "INPUT")]
+    pub input_file: String,
+}
@@ -0,0 +1,16 @@
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+pub struct WhichArgs {
+    /// Path to the game's folder containing CPK files
+    #[arg(short, long, value_name = "INPUT")]
+    pub input_folder: String,
+
+    /// The name of the file to look for, or a regex if --regex is set
+    #[arg(value_name = "FILE")]
+    pub file: String,
+
+    /// Optional: treat FILE as a regex matched against the file names
+    #[arg(short, long)]
+    pub regex: bool,
+}
@@ -0,0 +1,31 @@
+use std::path::{Path, PathBuf};
+
+use ievr_toolbox_core::{CpkIndex, crc32};
+
+use crate::CACHE_PATH;
+
+pub const CPK_LIST_NAME: &str = "cpk_list.cfg.bin";
+const CPK_INDEX_CACHE: &str = "cpk_index.json";
+
+pub fn cpk_index_cache_path() -> PathBuf {
+    PathBuf::from(CACHE_PATH).join(CPK_INDEX_CACHE)
+}
+
+/// The index of each game folder gets its own cache file, named after a hash of its full path,
+/// so that indexing another install (or another version to diff) doesn't overwrite it
+pub fn game_index_cache_path(data_folder: &Path) -> PathBuf {
+    let full_path = std::fs::canonicalize(data_folder).unwrap_or_else(|_| data_folder.to_path_buf());
+    let path_hash = crc32(full_path.to_string_lossy().as_bytes());
+    PathBuf::from(CACHE_PATH).join(format!("game_index_{path_hash:08x}.json"))
+}
+
+/// Loads the index of `cpk_list.cfg.bin` if the game folder contains one,
+/// reusing the cached copy when the list hasn't changed
+pub fn load_cpk_index(game_folder: &Path) -> std::io::Result<Option<CpkIndex>> {
+    let cpk_list_path = game_folder.join(CPK_LIST_NAME);
+    if !cpk_list_path.is_file() {
+        return Ok(None);
+    }
+
+    CpkIndex::load(&cpk_list_path, &cpk_index_cache_path()).map(Some)
+}
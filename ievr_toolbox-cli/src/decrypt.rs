@@ -1,10 +1,12 @@
 use std::{fs, path::PathBuf};
 
-use crate::DecryptArgs;
+use ievr_toolbox_core::CancellationToken;
+
+use crate::{DecryptArgs, progress::JsonProgress, report::{Event, Failure, Report}};
 
 const DECRYPTED_PATH: &str = "decrypted";
 
-pub fn decrypt(args: DecryptArgs) -> std::io::Result<()> {
+pub fn decrypt(args: DecryptArgs, cancellation: &CancellationToken, report: Report) -> std::io::Result<()> {
     let file_path_str = args.input_file.trim_matches('"').trim_end_matches("\\");
 
     let file_path = PathBuf::from(file_path_str);
@@ -23,12 +25,19 @@ pub fn decrypt(args: DecryptArgs) -> std::io::Result<()> {
         fs::create_dir_all(folder)?;
     }
 
-    let result = ievr_toolbox_core::decrypt(&file_path, &output_path);
+    let result = ievr_toolbox_core::decrypt(&file_path, &output_path, cancellation, &JsonProgress::new(report));
 
-    match &result {
-        Ok(()) => println!("File successfully decrypted to {}", output_path.display()),
-        Err(e) => println!("File decryption failed due to {e}"),
-    };
+    // The partial output was removed
+    if cancellation.is_cancelled() && result.is_err() {
+        report.fail(Failure::Cancelled, "File decryption cancelled");
+    }
+
+    if let Err(e) = result {
+        report.fail(Failure::of(&e), format!("File decryption failed due to {e}"));
+    }
+
+    report.text(format!("File successfully decrypted to {}", output_path.display()));
+    report.event(&Event::Decrypted { input: &file_path, output: &output_path });
 
-    result
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,196 @@
+use std::{collections::{HashMap, HashSet}, io, path::{Path, PathBuf}};
+
+use ievr_toolbox_core::{
+    ChangeKind, CpkIndex, Decompressor, FileChange, GameDiff, GameIndex, GameIndexMatch, Overrides, SelectionRules,
+    decompress_files, read_cpk_file,
+};
+
+use crate::{DiffArgs, cpk_index::{CPK_LIST_NAME, game_index_cache_path}, game_folder::game_data_folder, report::{Event, Report}};
+
+/// One side of the comparison. Saved indexes have no data folder, so their files can't be read.
+struct GameVersion {
+    index: GameIndex,
+    data_folder: Option<PathBuf>,
+}
+
+impl GameVersion {
+    fn load(input: &str) -> io::Result<GameVersion> {
+        let path = PathBuf::from(input.trim_matches('"'));
+        if path.is_file() {
+            return Ok(GameVersion { index: GameIndex::open(&path)?, data_folder: None });
+        }
+
+        let data_folder = game_data_folder(input)?;
+        let index = GameIndex::load(&data_folder, Some(&game_index_cache_path(&data_folder)))?;
+
+        Ok(GameVersion { index, data_folder: Some(data_folder) })
+    }
+
+    /// Which copy the game loads of the files in several CPKs, following `cpk_list.cfg.bin` when the version has one
+    fn overrides(&self, report: Report) -> Overrides {
+        let cpk_list_path = self.data_folder.as_ref()
+            .map(|data_folder| data_folder.join(CPK_LIST_NAME))
+            .filter(|cpk_list_path| cpk_list_path.is_file());
+
+        // Like the TOCs, the CPK lists of both versions would overwrite each other in the cache
+        let cpk_index = cpk_list_path.and_then(|cpk_list_path| match CpkIndex::from_cpk_list(&cpk_list_path) {
+            Ok(cpk_index) => Some(cpk_index),
+            Err(e) => {
+                report.warning(&format!("Unable to use {} ({e}), relying on the TOC of the CPKs instead", cpk_list_path.display()));
+                None
+            }
+        });
+
+        // The CPKs of a saved index are told apart by their full path
+        let data_folder = self.data_folder.as_deref().unwrap_or(Path::new(""));
+        Overrides::new(&self.index, data_folder, cpk_index.as_ref(), &[])
+    }
+}
+
+pub fn diff(args: DiffArgs, report: Report) -> std::io::Result<()> {
+    let old = GameVersion::load(&args.old)?;
+    let new = GameVersion::load(&args.new)?;
+
+    let mut rules = SelectionRules::default();
+    if !args.rules_file.is_empty() {
+        let rules_file_path = PathBuf::from(args.rules_file.trim_matches('"').trim_end_matches("\\"));
+
+        let invalid_rules;
+        (rules, invalid_rules) = SelectionRules::from_file(&rules_file_path)?;
+        for invalid_rule in invalid_rules {
+            report.warning(&format!("Ignoring {invalid_rule}"));
+        }
+    }
+
+    let (old_overrides, new_overrides) = (old.overrides(report), new.overrides(report));
+
+    let mut game_diff = GameDiff::compare(&old.index, &new.index, &old_overrides, &new_overrides, |file| {
+        rules.matches(&file.directory, &file.file_name, file.extract_size as u64)
+    });
+
+    let can_read_files = old.data_folder.is_some() && new.data_folder.is_some();
+    if !args.toc_only {
+        if can_read_files {
+            game_diff.compare_contents()?;
+        } else {
+            report.warning("A saved index has no files to read, only the TOCs are compared.");
+        }
+    }
+
+    for change in game_diff.changes() {
+        report.text(format_change(change));
+        report.event(&Event::FileChanged {
+            kind: change.kind,
+            path: &change.path,
+            old_size: change.old.map(|found| found.file.extract_size),
+            new_size: change.new.map(|found| found.file.extract_size),
+            old_crc32: change.old_crc32,
+            new_crc32: change.new_crc32,
+        });
+    }
+
+    let (added, removed, modified) = (
+        game_diff.count(ChangeKind::Added),
+        game_diff.count(ChangeKind::Removed),
+        game_diff.count(ChangeKind::Modified),
+    );
+    report.text(format!("\n{added} added, {removed} removed, {modified} modified"));
+    report.event(&Event::DiffSummary { added, removed, modified });
+
+    if let (Some(old_folder), Some(new_folder)) = (&old.data_folder, &new.data_folder) {
+        diff_cpk_lists(old_folder, new_folder, report)?;
+    }
+
+    if let Some(output_folder) = &args.output_folder {
+        let mut decompressor = Decompressor::default();
+
+        for change in game_diff.changes() {
+            if let (Some(found), Some(_)) = (change.old, &old.data_folder) {
+                extract_version(&mut decompressor, found, &output_folder.join("old"))?;
+            }
+            if let (Some(found), Some(_)) = (change.new, &new.data_folder) {
+                extract_version(&mut decompressor, found, &output_folder.join("new"))?;
+            }
+        }
+
+        report.text(format!("Changed files extracted to {}", output_folder.display()));
+        report.event(&Event::ChangesExtracted { output_folder });
+    }
+
+    Ok(())
+}
+
+fn format_change(change: &FileChange) -> String {
+    let crc32 = |crc32: Option<u32>| crc32.map_or_else(|| "-".to_string(), |crc32| format!("{crc32:08x}"));
+
+    match (change.kind, change.old, change.new) {
+        (ChangeKind::Added, _, Some(new)) => {
+            format!("+ {}\t{} bytes\t{}", change.path, new.file.extract_size, crc32(change.new_crc32))
+        }
+        (ChangeKind::Removed, Some(old), _) => {
+            format!("- {}\t{} bytes\t{}", change.path, old.file.extract_size, crc32(change.old_crc32))
+        }
+        (_, Some(old), Some(new)) => format!(
+            "~ {}\t{} -> {} bytes ({:+})\t{} -> {}",
+            change.path,
+            old.file.extract_size,
+            new.file.extract_size,
+            change.size_delta(),
+            crc32(change.old_crc32),
+            crc32(change.new_crc32),
+        ),
+        _ => unreachable!("A change always has the versions matching its kind"),
+    }
+}
+
+fn extract_version(decompressor: &mut Decompressor, found: GameIndexMatch, output_folder: &Path) -> io::Result<()> {
+    let file = read_cpk_file(&found.cpk.path, &found.cpk_file())?;
+    decompress_files(decompressor, &file, &output_folder.to_path_buf())?;
+    Ok(())
+}
+
+/// Reports the files that were added to, removed from or moved between CPKs in `cpk_list.cfg.bin`
+fn diff_cpk_lists(old_folder: &Path, new_folder: &Path, report: Report) -> io::Result<()> {
+    let (old_list, new_list) = (old_folder.join(CPK_LIST_NAME), new_folder.join(CPK_LIST_NAME));
+    if !old_list.is_file() || !new_list.is_file() {
+        return Ok(());
+    }
+
+    let cpk_by_file = |index: &CpkIndex| -> HashMap<String, HashSet<String>> {
+        let mut cpk_by_file: HashMap<String, HashSet<String>> = HashMap::new();
+        for entry in index.entries() {
+            cpk_by_file.entry(entry.file_name.clone()).or_default().insert(entry.cpk_name.clone());
+        }
+        cpk_by_file
+    };
+
+    let old_cpks = cpk_by_file(&CpkIndex::from_cpk_list(&old_list)?);
+    let new_cpks = cpk_by_file(&CpkIndex::from_cpk_list(&new_list)?);
+
+    let added = new_cpks.keys().filter(|file_name| !old_cpks.contains_key(*file_name)).count();
+    let removed = old_cpks.keys().filter(|file_name| !new_cpks.contains_key(*file_name)).count();
+
+    let mut moved: Vec<(&String, &HashSet<String>, &HashSet<String>)> = old_cpks.iter()
+        .filter_map(|(file_name, old)| {
+            new_cpks.get(file_name)
+                .filter(|new| *new != old)
+                .map(|new| (file_name, old, new))
+        })
+        .collect();
+    moved.sort_by(|a, b| a.0.cmp(b.0));
+
+    report.text(format!("\n{CPK_LIST_NAME}: {added} files added, {removed} removed, {} moved to another CPK", moved.len()));
+    report.event(&Event::CpkListChanged { added, removed, moved: moved.len() });
+    for (file_name, old, new) in moved {
+        report.text(format!("> {file_name}\t{} -> {}", sorted(old).join(", "), sorted(new).join(", ")));
+        report.event(&Event::FileMoved { file_name, old_cpks: sorted(old), new_cpks: sorted(new) });
+    }
+
+    Ok(())
+}
+
+fn sorted(names: &HashSet<String>) -> Vec<&str> {
+    let mut names: Vec<&str> = names.iter().map(String::as_str).collect();
+    names.sort();
+    names
+}
@@ -0,0 +1,26 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use serde::Serialize;
+use sysinfo::Disks;
+
+/// A mounted disk and its free space
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Disk {
+    pub mount_point: PathBuf,
+    pub available: u64,
+}
+
+/// The disk holding `path`, or its closest existing parent folder if it wasn't created yet
+pub fn disk_of(path: &Path) -> Option<Disk> {
+    let path = fs::canonicalize(path).ok()
+        .or_else(|| path.ancestors().skip(1).find_map(|parent| fs::canonicalize(parent).ok()))
+        .or_else(|| std::env::current_dir().ok())?;
+
+    let disks = Disks::new_with_refreshed_list();
+    disks.list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| Disk { mount_point: disk.mount_point().to_path_buf(), available: disk.available_space() })
+}
+
@@ -1,76 +1,148 @@
-use crossbeam;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use regex::Regex;
 use sysinfo::{MemoryRefreshKind, RefreshKind, System};
 
 use std::{
-    collections::BinaryHeap, fs::{self, DirBuilder, File}, io::{self, BufRead, BufReader}, path::{Path, PathBuf}, process::exit, thread, time::Instant
+    collections::HashSet, fs::{self, DirBuilder}, io, path::{Path, PathBuf}, sync::Arc, time::Instant
 };
 
-use ievr_cfg_bin_editor_core::{Database, Value, parse_database};
-
-use crate::{GB, MB, TMP_PATH, args::DumpArgs, memory_budget::MemoryPool};
+use crate::{GB, MB, args::{DumpArgs, OutputFormat, ZipCompressionArg}, cpk_index::{game_index_cache_path, load_cpk_index}, dump_state::{DumpState, IncrementalDump, cpk_list_crc32, dump_state_path}, game_folder::game_data_folder, journal::{Journal, PreviousDump, journal_path}, manifest::{ManifestRecord, ManifestWriter}, disk_space::disk_of, performance::{print_performance, write_performance}, preflight::{DumpPlan, format_size, format_usage}, priority::lower_priority, progress::{DumpProgressBars, JsonProgress}, report::{Event, Report}};
 
 use ievr_toolbox_core::{
-    CpkFile, Decompressor, DecryptedCpk, TocParser, decompress_files, decrypt_cpk,
-    extract_cpk_files,
+    BlobSink, CancellationToken, CpkFile, Decompressor, DecryptCache, DumpPipeline, DumpStats, FileSelection, FolderSink, GameIndex, OutputSink,
+    Overrides, ProgressSink, QueueMetrics, SelectionRules, TarSink, ZipCompression, ZipSink, find_cpk_files, is_compressed, relative_cpk_path,
 };
 
-pub fn dump(args: DumpArgs) -> std::io::Result<()> {
-    // Access the folder path
-    let game_path = args.input_folder.trim_matches('"').trim_end_matches("\\"); // This removes all quotes and trailing backslashes
-
-    let mut game_folder = PathBuf::from(game_path);
-
-    if !game_folder.exists() {
-        eprintln!("Error: The path {} does not exist.", game_folder.display());
-        std::process::exit(1);
+pub fn dump(args: DumpArgs, cancellation: &CancellationToken, report: Report) -> std::io::Result<()> {
+    // Archives are written from scratch, and only hold the files loaded by the game
+    if args.output_format != OutputFormat::Folder {
+        let folder_only = [("--resume", args.resume), ("--incremental", args.incremental), ("--shadow-folder", args.shadow_folder.is_some())];
+        if let Some((option, _)) = folder_only.iter().find(|(_, used)| *used) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{option} can only be used with --output-format folder")));
+        }
     }
 
-    println!("Scanning game folder: {}", game_folder.display());
+    // Access the folder path
+    let game_folder = game_data_folder(&args.input_folder)?;
 
-    if !game_folder.ends_with("data") {
-        game_folder.push("data");
-    }
+    report.text(format!("Scanning game folder: {}", game_folder.display()));
 
     let mut dir_builder = DirBuilder::new();
     dir_builder.recursive(true);
 
-    let temp_folder = PathBuf::from(TMP_PATH);
-    if temp_folder.exists() {
-        fs::remove_dir_all(&temp_folder).unwrap();
-    }
-    
-    dir_builder.create(TMP_PATH)?;
-    
+    let temp_folder = args.temp_folder.clone();
+    let decrypt_cache = DecryptCache::new(&temp_folder);
+    let cache_size = (args.cache_size * GB as f64) as u64;
+
     let extract_folder = &args.output_folder;
-    if !extract_folder.exists() {
-        dir_builder.create(extract_folder)?;
-    }
 
-    let mut files_to_process = Vec::new();
-    visit_dirs(&game_folder, &mut |path| {
-        if let Some(ext) = path.extension() {
-            if ext.to_string_lossy().to_lowercase() == "cpk" {
-                files_to_process.push(path);
-            }
+    let journal_path = journal_path(extract_folder);
+    let previous_dump = if args.resume {
+        Some(Arc::new(PreviousDump::load(&journal_path)?))
+    } else {
+        None
+    };
+
+    let mut files_to_process = find_cpk_files(&game_folder)?;
+
+    // Only the header and TOC of each CPK are decrypted to index the game, and the index is cached
+    let game_index = GameIndex::load(&game_folder, Some(&game_index_cache_path(&game_folder)))?;
+
+    let cpk_index = match load_cpk_index(&game_folder) {
+        Ok(cpk_index) => cpk_index,
+        Err(e) => {
+            report.warning(&format!("Unable to use cpk_list.cfg.bin ({e}), relying on the TOC of the CPKs instead"));
+            None
         }
-    })?;
+    };
+
+    let cpk_priority = match &args.cpk_priority {
+        Some(path) => read_cpk_priority(path)?,
+        None => Vec::new(),
+    };
+
+    let overrides = Arc::new(Overrides::new(&game_index, &game_folder, cpk_index.as_ref(), &cpk_priority));
+    if !overrides.is_empty() {
+        match &args.shadow_folder {
+            Some(shadow_folder) => report.text(format!(
+                "{} files are in several CPKs, the {} overridden copies are extracted to {}",
+                overrides.len(),
+                overrides.overridden_count(),
+                shadow_folder.display(),
+            )),
+            None => report.text(format!(
+                "{} files are in several CPKs, only the copies loaded by the game are extracted",
+                overrides.len(),
+            )),
+        }
+    }
 
-    let mut selected_files = Vec::new();
+    // Without rules, every file is extracted
+    let mut selection = None;
     if args.rules_file != "" {
         let rules_file_path = PathBuf::from(args.rules_file.trim_matches('"').trim_end_matches("\\")); // This removes all quotes and trailing backslashes
-        let rules_file = File::open(rules_file_path).unwrap();
 
-        let mut cpk_list_path = game_folder.to_path_buf();
-        cpk_list_path.push("cpk_list.cfg.bin");
+        let (rules, invalid_rules) = SelectionRules::from_file(&rules_file_path)?;
+        for invalid_rule in invalid_rules {
+            report.warning(&format!("Ignoring {invalid_rule}"));
+        }
 
-        let cpk_list = decrypt_cpk(&cpk_list_path, &temp_folder, 1 * GB);
+        let file_selection = cpk_index.as_ref()
+            .and_then(|cpk_index| FileSelection::from_cpk_index(cpk_index, &rules))
+            .unwrap_or_else(|| FileSelection::from_game_index(&game_index, &rules));
 
-        let cpk_list_database = parse_database(&cpk_list).unwrap();        
+        files_to_process.retain(|cpk_file| file_selection.contains_cpk(&relative_cpk_path(&game_folder, cpk_file)));
 
-        (files_to_process, selected_files) = select_requested_cpks(cpk_list_database, files_to_process, rules_file);
+        selection = Some(file_selection);
     }
+
+    if let Some(previous_dump) = &previous_dump {
+        let cpk_count = files_to_process.len();
+        files_to_process.retain(|cpk_file| !previous_dump.is_cpk_done(&relative_cpk_path(&game_folder, cpk_file)));
+
+        report.text(format!("Resuming: {} CPK files were already extracted", cpk_count - files_to_process.len()));
+    }
+
+    let mut incremental = None;
+    let mut current_cpk_list_crc32 = None;
+    let mut removed_files = Vec::new();
+    if args.incremental {
+        let previous_state = DumpState::load(&dump_state_path(extract_folder));
+        current_cpk_list_crc32 = cpk_list_crc32(&game_folder);
+
+        let mut changed_cpks: HashSet<String> = game_index.cpks()
+            .iter()
+            .filter(|cpk| previous_state.is_cpk_changed(cpk))
+            .map(|cpk| cpk.relative_path.clone())
+            .collect();
+
+        if current_cpk_list_crc32 != previous_state.cpk_list_crc32() {
+            report.text("cpk_list.cfg.bin changed since the previous dump");
+        }
+
+        // The CPK whose copy of a file is loaded can change without the CPKs changing
+        let new_winners = previous_state.cpks_with_new_winners(&game_index, &overrides);
+        if !new_winners.is_empty() {
+            report.text(format!("{} CPK files now hold the copies loaded by the game of files extracted from other CPKs", new_winners.len()));
+            changed_cpks.extend(new_winners);
+        }
+        report.text(format!(
+            "Incremental dump: {} of {} CPK files changed since the previous dump",
+            changed_cpks.len(),
+            game_index.cpks().len(),
+        ));
+
+        // The files are only removed once the dump is sure to start
+        removed_files = previous_state.removed_files(&game_index).map(str::to_string).collect();
+        if !removed_files.is_empty() && !args.remove_deleted {
+            report.text(format!("{} files are no longer in the game, use --remove-deleted to delete them", removed_files.len()));
+        } else if !removed_files.is_empty() && args.dry_run {
+            report.text(format!("{} files that are no longer in the game would be removed", removed_files.len()));
+        }
+
+        files_to_process.retain(|cpk_file| changed_cpks.contains(&relative_cpk_path(&game_folder, cpk_file)));
+
+        incremental = Some(Arc::new(IncrementalDump::new(previous_state, &game_index)));
+    }
+
     // We sort the work by biggest files first
 
     files_to_process.sort_by_key(|p| std::fs::metadata(p).map(|m| m.len()).unwrap());
@@ -82,11 +154,11 @@ pub fn dump(args: DumpArgs) -> std::io::Result<()> {
         .map(|path| fs::metadata(path).unwrap().len())
         .sum();
 
-    println!(
-        "Found {} CPK files ({:.2} GiB) to extract. Starting extraction...\n",
+    report.text(format!(
+        "Found {} CPK files ({:.2} GiB) to extract.\n",
         total_files,
         total_file_size as f64 / GB as f64
-    );
+    ));
 
     // We compute the number of threads allocated to the program
 
@@ -100,8 +172,6 @@ pub fn dump(args: DumpArgs) -> std::io::Result<()> {
         args.threads
     };
 
-    let (decrypt_threads, extract_threads, decompress_threads) = compute_threads(threads_in_use);
-
     // We compute the memory limits based on the memory allocated to the program
 
     let system = System::new_with_specifics(
@@ -116,315 +186,402 @@ pub fn dump(args: DumpArgs) -> std::io::Result<()> {
         (args.memory * GB as f64) as usize
     };    
 
-    let size_threshold = memory / decrypt_threads / 2; // We want to avoid the situation where the CPK + the files it contains go over the limit
-    let memory_pool = MemoryPool::new(memory);
+    // About half the threads decrypt at once, and we want to avoid the situation where the CPK + the files it contains go over the limit
+    let size_threshold = memory / threads_in_use.div_ceil(2) / 2;
 
-    println!("Memory allocated: {:.2} GiB - In-RAM decryption threshold: {} MiB\n", 
-        memory as f64 / GB as f64,
-        size_threshold / MB,
-    );
+    // The selected TOCs tell exactly how much will be written, which is checked before starting
 
-    // We display the current settings
+    let plan = DumpPlan::new(&game_index, &files_to_process, selection.as_ref(), &overrides, &args, size_threshold, threads_in_use);
 
-    println!(
-        "Decryption threads: {}",
-        decrypt_threads,
-    );
-    println!("Extraction threads: {:?}", extract_threads);
-    println!(
-        "Decompression threads: {}\n",
-        decompress_threads,
-    );
+    // The CPKs too big to be decrypted in RAM go to the temp folder, within the disk budget.
+    // When the output folder is on the same disk, the space its files will take is left out of the budget,
+    // as are the spill files of an archive.
 
-    // We create the channels that will be used to communicate
+    let available_disk = disk_of(&temp_folder).map_or(usize::MAX, |disk| {
+        disk.available.saturating_sub(plan.output_needed_on(&disk, &args) + plan.spill_peak()) as usize
+    });
 
-    let (dec_tx, dec_rx) = crossbeam::channel::unbounded::<DecryptedCpk>();
-    let (ext_tx, ext_rx) = crossbeam::channel::unbounded::<CpkFile>();
-
-    // We store the handles to the threads to be able to wait for them to finish
-
-    let mut decrypt_handles = Vec::with_capacity(decrypt_threads);
-    let mut extract_handles = Vec::with_capacity(extract_threads);
-    let mut decompress_handles = Vec::with_capacity(decompress_threads);
+    let disk_space = if args.disk == 0.0 || args.disk * GB as f64 > available_disk as f64 {
+        available_disk
+    } else {
+        (args.disk * GB as f64) as usize
+    };
 
-    // We setup the progress bars
+    report.text(format!("Memory allocated: {:.2} GiB - In-RAM decryption threshold: {} MiB",
+        memory as f64 / GB as f64,
+        size_threshold / MB,
+    ));
+    if disk_space == usize::MAX {
+        report.text("Disk space allocated to temporary files: unknown, no limit\n");
+    } else {
+        report.text(format!("Disk space allocated to temporary files: {:.2} GiB\n", disk_space as f64 / GB as f64));
+    }
 
-    let start_time = Instant::now();
+    // We display the current settings
 
-    let mp = MultiProgress::new();
-
-    let decryption_pb = mp.add(ProgressBar::new(total_file_size));
-    decryption_pb.set_style(ProgressStyle::with_template(
-        "{spinner:.green} Decrypting CPKs [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
-    )
-    .unwrap()
-    .progress_chars("#>-"));
-    decryption_pb.enable_steady_tick(std::time::Duration::from_millis(100));
-
-    let extract_pb = mp.add(ProgressBar::new(0));
-    extract_pb.set_style(ProgressStyle::with_template(
-        "{spinner:.green} Extracting files [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
-    )
-    .unwrap()
-    .progress_chars("#>-"));
-    extract_pb.enable_steady_tick(std::time::Duration::from_millis(100));
-
-    for i in 0..decrypt_threads {
-        let tx = dec_tx.clone();
-        let cpk_files = files_to_process.clone();
-        let temp_folder = temp_folder.clone();
-        let decrypt_pb = decryption_pb.clone();
-        let memory_pool = memory_pool.clone();
-
-        decrypt_handles.push(thread::spawn(move || {
-            for original_file in cpk_files.iter().skip(i).step_by(decrypt_threads) {
-                let file_size = fs::metadata(original_file).unwrap().len() as usize;
-                if file_size < size_threshold {
-                    // This will map the file to RAM instead of a file
-                    memory_pool.acquire_decryption(file_size as usize);
-                }
+    let max_open_cpks = if args.max_open_cpks == 0 { threads_in_use * 2 } else { args.max_open_cpks };
 
-                let decrypted_cpk = decrypt_cpk(&original_file, &temp_folder, size_threshold);
+    report.text(format!("Threads: {threads_in_use}, shared between decryption and decompression - At most {max_open_cpks} decrypted CPKs open at once\n"));
 
-                decrypt_pb.inc(file_size as u64);
+    let max_io = (args.max_io * MB as f64) as usize;
+    if max_io > 0 {
+        report.text(format!("Reads and writes limited to {:.2} MiB/s", args.max_io));
+    }
 
-                tx.send(decrypted_cpk).unwrap();
-            }
-        }));
+    // The priority is inherited by the threads of the dump, which aren't started yet
+    if args.background {
+        match lower_priority() {
+            Ok(()) => report.text("Running in the background, with the lowest CPU and disk priority"),
+            Err(e) => report.warning(&format!("Unable to lower the priority of the dump ({e}), running it normally")),
+        }
+    }
+    if max_io > 0 || args.background {
+        report.text("");
     }
 
-    // Extractor thread
-    {
-        let dec_rx = dec_rx.clone();
-        let ext_tx = ext_tx.clone();
-        let extract_pb = extract_pb.clone();
-
-        extract_handles.push(thread::spawn(move || {
-            let mut toc_parser = TocParser::default();
-            let mut heap = BinaryHeap::<CpkFile>::new();
-            let mut extraction_done = false;
-
-            loop {
-                if extraction_done {
-                    while let Some(extracted_file) = heap.pop() {
-                        extract_pb.inc_length(extracted_file.extract_size as u64);
-                        // If receiver hangs up, stop sending
-                        if ext_tx.send(extracted_file).is_err() {
-                            break;
-                        }
-                    }
-                    break;
-                }
+    let disk_usage = plan.disk_usage(&args, disk_space as u64);
+    report.event(&plan.event(&disk_usage, disk_space as u64));
 
-                // Phase 2: If Heap is Empty, we MUST block.
-                // If we don't block here, we hit the 'default' branch below instantly and spin the CPU.
-                if heap.is_empty() {
-                    match dec_rx.recv() {
-                        Ok(decrypted_file) => {
-                            for extracted_file in extract_cpk_files(decrypted_file, &mut toc_parser) {
-                                if selected_files.is_empty() || selected_files.contains(&extracted_file.file_name) {
-                                    heap.push(extracted_file);
-                                }
-                            };
-                        }
-                        Err(_) => extraction_done = true,
-                    }
-                    continue;
-                }
+    if args.dry_run {
+        if !report.json() {
+            plan.print(&args, disk_space as u64);
+        }
+        report.text("Dry run, nothing was extracted.");
+        report.event(&Event::DumpFinished {
+            dry_run: true,
+            file_count: plan.file_count(),
+            output_size: plan.output_size(),
+            duration_secs: 0.0,
+            cached_size: 0,
+            peak_files_waiting: 0,
+            peak_cpks_open: 0,
+        });
+        return Ok(());
+    }
 
-                // Phase 3: Heap has items, and Input is still open.
-                // We prioritize checking for new data (to keep the pipe full), 
-                // but default to sending data if no new messages are ready.
-                crossbeam::select! {
-                    recv(dec_rx) -> msg => {
-                        match msg {
-                            Ok(decrypted_file) => {
-                                for extracted_file in extract_cpk_files(decrypted_file, &mut toc_parser) {
-                                    if selected_files.is_empty() || selected_files.contains(&extracted_file.file_name) {
-                                        heap.push(extracted_file);
-                                    }
-                                }
-                            },
-                            Err(_) => extraction_done = true,
-                        }
-                    }
-                    default => {
-                        // We know heap is not empty because of the check in Phase 2
-                        if let Some(extracted_file) = heap.pop() {
-                            extract_pb.inc_length(extracted_file.extract_size as u64);
-                            if ext_tx.send(extracted_file).is_err() {
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
-        }));
+    if disk_usage.iter().any(|usage| !usage.fits()) || plan.largest_on_disk() > disk_space as u64 {
+        let mut message = vec!["Insufficient disk space for the dump, aborting...".to_string()];
+        message.extend(disk_usage.iter().map(format_usage));
+        if plan.largest_on_disk() > disk_space as u64 {
+            message.push(format!(
+                "The biggest CPK to decrypt to the temp folder takes {}, but only {} are allocated to temporary files",
+                format_size(plan.largest_on_disk()),
+                format_size(disk_space as u64),
+            ));
+        }
+        return Err(io::Error::new(io::ErrorKind::StorageFull, message.join("\n")));
     }
-    
-
-    for _ in 0..decompress_threads {
-        let ext_rx = ext_rx.clone();
-        let extract_folder = extract_folder.clone();
-        let extract_pb = extract_pb.clone();
-        let memory_pool = memory_pool.clone();
-
-        decompress_handles.push(thread::spawn(move || {
-            let mut decompressor = Decompressor::default();
-            while let Ok(extracted_file) = ext_rx.recv() {
-                if extracted_file.extract_size as usize > memory_pool.limit() {
-                    extract_pb.finish_and_clear();
-                    eprintln!("Insufficient memory allocation for decompression, aborting...");
-                    exit(1);
-                }
-                memory_pool.acquire_decompression(extracted_file.extract_size as usize);
 
-                decompress_files(&mut decompressor, &extracted_file, &extract_folder);
+    if args.remove_deleted && !removed_files.is_empty() {
+        for path in &removed_files {
+            // The file may have been deleted by hand already
+            let _ = fs::remove_file(extract_folder.join(path));
+        }
+        report.text(format!("Removed {} files that are no longer in the game", removed_files.len()));
+    }
 
-                memory_pool.release(extracted_file.extract_size as usize);
+    report.text(format!(
+        "Extracting {} files ({:.2} GiB). Starting extraction...\n",
+        plan.file_count(),
+        plan.output_size() as f64 / GB as f64,
+    ));
+    let archive_path = archive_path(&args);
+    if let Some(archive_path) = &archive_path {
+        report.text(format!("Writing the files to {}\n", archive_path.display()));
+    }
 
-                let file_size = extracted_file.cpk_size().unwrap();
-                if let Some(true) = extracted_file.last_cpk_file() && file_size < size_threshold {
-                    // If it has been mapped to RAM
-                    memory_pool.release(file_size);
-                }
+    // Decrypted CPKs left by previous dumps are kept as long as their source didn't change
+    dir_builder.create(&temp_folder)?;
+    decrypt_cache.clean()?;
 
-                extract_pb.inc(extracted_file.extract_size as u64);
+    match &archive_path {
+        Some(archive_path) => {
+            if let Some(parent) = archive_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                dir_builder.create(parent)?;
             }
-        }));
-    }
-
-    for dec_handle in decrypt_handles {
-        dec_handle.join().unwrap();
+        }
+        None => {
+            if !extract_folder.exists() {
+                dir_builder.create(extract_folder)?;
+            }
+        }
     }
 
-    decryption_pb.finish();
+    // An archive can't be resumed, so it has no journal
+    let journal = match &archive_path {
+        Some(_) => None,
+        None => Some(Journal::open(&journal_path, args.resume)?),
+    };
 
-    drop(dec_tx);
+    let manifest = match &args.manifest {
+        Some(path) => Some(ManifestWriter::create(path)?),
+        None => None,
+    };
 
-    for _ in 0..decrypt_threads {
-        let ext_rx = ext_rx.clone();
-        let extract_folder = extract_folder.clone();
-        let extract_pb = extract_pb.clone();
-        let memory_pool = memory_pool.clone();
+    // We setup the progress bars
 
-        decompress_handles.push(thread::spawn(move || {
-            let mut decompressor = Decompressor::default();
-            while let Ok(extracted_file) = ext_rx.recv() {
-                if extracted_file.extract_size as usize > memory_pool.limit() {
-                    extract_pb.finish_and_clear();
-                    eprintln!("Insufficient memory allocation for decompression, aborting...");
-                    exit(1);
-                }
-                memory_pool.acquire_decompression(extracted_file.extract_size as usize);
+    let start_time = Instant::now();
 
-                decompress_files(&mut decompressor, &extracted_file, &extract_folder);
+    let queue_metrics = QueueMetrics::default();
+    let dump_stats = DumpStats::default();
+    let progress_bars = (!report.json()).then(|| Arc::new(DumpProgressBars::new(total_file_size, &queue_metrics)));
+    let progress: Arc<dyn ProgressSink> = match &progress_bars {
+        Some(progress_bars) => progress_bars.clone(),
+        None => Arc::new(JsonProgress::new(report)),
+    };
 
-                memory_pool.release(extracted_file.extract_size as usize);
+    // Copies overridden by another CPK are left out unless they go to the shadow folder
+    let keep_overridden = args.shadow_folder.is_some();
+    let filter_overrides = overrides.clone();
+    let filter = move |file: &CpkFile| {
+        selection.as_ref().is_none_or(|selection| selection.contains_file(file))
+            && (keep_overridden || !filter_overrides.is_overridden(file))
+    };
 
-                let file_size = extracted_file.cpk_size().unwrap();
-                if extracted_file.last_cpk_file().unwrap() && file_size < size_threshold {
-                    // If it has been mapped to RAM
-                    memory_pool.release(file_size);
-                }
+    // The journal validates the files with their checksum when resuming, and the manifest and the incremental state record it
+    let checksums = journal.is_some() || manifest.is_some() || incremental.is_some();
+
+    let output = Arc::new(DumpOutput {
+        writer: output_writer(&args, archive_path.as_deref(), &temp_folder)?,
+        extract_folder: extract_folder.clone(),
+        shadow_folder: args.shadow_folder.clone(),
+        overrides,
+        journal,
+        manifest: manifest.clone(),
+        previous_dump,
+        incremental: incremental.clone(),
+    });
+
+    let result = DumpPipeline::new(files_to_process, output)
+        .data_folder(&game_folder)
+        .threads(threads_in_use)
+        .memory_limit(memory)
+        .disk_limit(disk_space)
+        .size_threshold(size_threshold)
+        .max_open_cpks(max_open_cpks)
+        .max_io(max_io)
+        .temp_folder(&temp_folder)
+        .cache_size(cache_size as usize)
+        .checksums(checksums)
+        .filter(filter)
+        .progress(progress)
+        .metrics(&queue_metrics)
+        .stats(&dump_stats)
+        .cancellation(cancellation)
+        .run();
+
+    if let Some(progress_bars) = &progress_bars {
+        if cancellation.is_cancelled() || result.is_err() {
+            progress_bars.clear();
+        } else {
+            progress_bars.finish();
+        }
+    }
 
-                extract_pb.inc(extracted_file.extract_size as u64);
-            }
-        }));
+    // The manifest and the cache are left in order even when the dump stops early
+    if let Some(manifest) = manifest {
+        manifest.finish()?;
     }
 
-    for ext_handle in extract_handles {
-        ext_handle.join().unwrap();
+    // Only the most recently used copies that fit in the cache are kept for the next dumps
+    decrypt_cache.evict(cache_size)?;
+    if cache_size == 0 {
+        let _ = fs::remove_dir(decrypt_cache.folder());
+        let _ = fs::remove_dir(&temp_folder);
     }
 
-    drop(ext_tx);
+    // The journal already has every file written, so the dump can be resumed from there.
+    // The incremental state is only saved by a complete dump.
+    if cancellation.is_cancelled() {
+        let message = match &archive_path {
+            Some(archive_path) => format!("Dump cancelled after {:.2?}, {} holds the files written so far", start_time.elapsed(), archive_path.display()),
+            None => format!("Dump cancelled after {:.2?}, run it again with --resume to continue it", start_time.elapsed()),
+        };
+        return Err(io::Error::new(io::ErrorKind::Interrupted, message));
+    }
 
-    for decomp_handle in decompress_handles {
-        decomp_handle.join().unwrap();
+    if let Err(e) = result {
+        return Err(io::Error::new(e.kind(), format!("{e}, aborting...")));
     }
 
-    extract_pb.finish();
+    if let Some(incremental) = incremental {
+        // Every worker is done, so this is the last reference
+        let incremental = Arc::into_inner(incremental).unwrap();
+        incremental.finish(&game_index, current_cpk_list_crc32, args.remove_deleted)
+            .save(&dump_state_path(extract_folder))?;
+    }
 
-    fs::remove_dir_all(temp_folder).unwrap();
+    let cached_size = if cache_size == 0 {
+        0
+    } else {
+        let cached_size = decrypt_cache.size()?;
+        report.text(format!("Decrypted CPKs cached in {}: {:.2} GiB", decrypt_cache.folder().display(), cached_size as f64 / GB as f64));
+        cached_size
+    };
 
     let duration = start_time.elapsed();
-
-    println!("\n--- Extraction Summary ---");
-    println!("Total time: {:.2?}", duration);
-
-    Ok(())
-}
-
-fn visit_dirs(dir: &Path, cb: &mut dyn FnMut(PathBuf)) -> io::Result<()> {
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                // Recursively call the function for subdirectories
-                visit_dirs(&path, cb)?;
+    let depths = queue_metrics.depths();
+
+    report.text("\n--- Extraction Summary ---");
+    report.text(format!("Total time: {:.2?}", duration));
+    report.text(format!(
+        "Most files waiting: {} - Most decrypted CPKs open: {} ({} being decrypted)",
+        depths.peak_files_waiting,
+        depths.peak_cpks_open,
+        depths.peak_cpks_decrypting,
+    ));
+
+    report.event(&Event::DumpFinished {
+        dry_run: false,
+        file_count: plan.file_count(),
+        output_size: plan.output_size(),
+        duration_secs: duration.as_secs_f64(),
+        cached_size,
+        peak_files_waiting: depths.peak_files_waiting,
+        peak_cpks_open: depths.peak_cpks_open,
+    });
+
+    if args.stats || args.stats_file.is_some() {
+        let performance = dump_stats.report();
+        if args.stats {
+            if report.json() {
+                report.event(&Event::Performance { report: &performance });
             } else {
-                // It's a file, execute the callback
-                cb(path);
+                print_performance(&performance);
             }
         }
+        if let Some(stats_file) = &args.stats_file {
+            write_performance(&performance, stats_file)?;
+        }
     }
+
     Ok(())
 }
 
-fn compute_threads(threads_in_use: usize) -> (usize, usize, usize) {
-    // We only want 1 extraction thread because extraction is so fast,
-    // it doesn't copy anything. It simply extracts metadata from
-    // the decrypted CPK and reorganizes it
-    let extract_threads = 1;
+/// Where the archive is written, which is the output path with the extension of the format
+/// if it has none, or `None` when the files are written in the output folder
+pub fn archive_path(args: &DumpArgs) -> Option<PathBuf> {
+    let extension = args.output_format.extension()?;
+
+    let mut archive_path = args.output_folder.clone();
+    if archive_path.extension().is_none() {
+        archive_path.set_extension(extension);
+    }
+    Some(archive_path)
+}
+
+/// Creates what writes the files to the output folder or to the archive. The files too big
+/// for the memory budget are decompressed to the temp folder before being copied to an archive.
+fn output_writer(args: &DumpArgs, archive_path: Option<&Path>, temp_folder: &Path) -> io::Result<Box<dyn OutputSink>> {
+    let Some(archive_path) = archive_path else {
+        return Ok(Box::new(FolderSink::new(&args.output_folder)));
+    };
+
+    let compression = match args.zip_compression {
+        ZipCompressionArg::Store => ZipCompression::Store,
+        ZipCompressionArg::Deflate => ZipCompression::Deflate,
+    };
 
-    let decrypt_threads = threads_in_use / 2;
-    let decompress_threads = threads_in_use - decrypt_threads - extract_threads;
+    Ok(match args.output_format {
+        OutputFormat::Folder => unreachable!("the folder format has no archive"),
+        OutputFormat::Tar => Box::new(TarSink::create(archive_path)?.spill_folder(temp_folder)),
+        OutputFormat::Zip => Box::new(ZipSink::create(archive_path, compression)?.spill_folder(temp_folder)),
+        OutputFormat::Blob => Box::new(BlobSink::create(archive_path)?.spill_folder(temp_folder)),
+    })
+}
 
-    (decrypt_threads, extract_threads, decompress_threads.max(1))
+/// Reads the CPK names of a priority file, skipping blank lines and `#` comments
+fn read_cpk_priority(path: &Path) -> std::io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
 }
 
-fn select_requested_cpks(cpk_list: Database, mut cpk_files: Vec<PathBuf>, rules_file: File) -> (Vec<PathBuf>, Vec<String>) {
-    let mut selected_cpk = Vec::new();
-    let mut selected_files = Vec::new();
+/// Writes the extracted files to the output folder or the archive, or the overridden copies to the shadow folder,
+/// and keeps the journal, the manifest and the incremental state up to date
+struct DumpOutput {
+    writer: Box<dyn OutputSink>,
+    extract_folder: PathBuf,
+    shadow_folder: Option<PathBuf>,
+    overrides: Arc<Overrides>,
+    journal: Option<Journal>,
+    manifest: Option<ManifestWriter>,
+    previous_dump: Option<Arc<PreviousDump>>,
+    incremental: Option<Arc<IncrementalDump>>,
+}
 
-    let buf_reader = BufReader::new(rules_file);
+impl OutputSink for DumpOutput {
+    fn begin_cpk(&self, cpk_name: &Arc<str>, file_count: usize) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.expect(cpk_name, file_count),
+            None => Ok(()),
+        }
+    }
 
-    let cpk_table = cpk_list.table("CPK_ITEM").unwrap();
+    fn finish(&self) -> io::Result<()> {
+        self.writer.finish()
+    }
 
-    let lines = buf_reader.lines();
-    for regex in lines.map_while(Result::ok) {
-        let re = match Regex::new(&regex) {
-            Ok(re) => re,
-            Err(_) => {
-                eprintln!("Invalid regex {regex}, ignoring it...");
-                continue
-            }
-        };
+    fn write_file(&self, decompressor: &mut Decompressor, extracted_file: &CpkFile) -> io::Result<Option<u32>> {
+        let decompress_start = Instant::now();
 
-        for row in cpk_table.rows() {
-            let file_name = match &row.values[1][0] {
-                Value::String(s) => s.clone(),
-                _ => continue,
-            };
+        // Copies overridden by another CPK only get here if they go to the shadow folder
+        let shadow_folder = self.shadow_folder.as_ref()
+            .filter(|_| self.overrides.is_overridden(extracted_file))
+            .map(|shadow_folder| shadow_folder.join(extracted_file.cpk_name.as_deref().unwrap_or_default()));
 
-            let cpk_name = match &row.values[3][0] {
-                Value::String(s) => s.clone(),
-                _ => continue,
-            };
+        let previous_crc32 = self.previous_dump.as_ref()
+            .filter(|_| shadow_folder.is_none())
+            .and_then(|previous_dump| previous_dump.extracted_crc32(extracted_file, &self.extract_folder));
 
-            if re.is_match(&file_name) {
-                selected_files.push(file_name.clone());
-                selected_cpk.push(cpk_name.clone());
+        let crc32 = match previous_crc32 {
+            Some(crc32) => {
+                // Only a dump to the output folder can be resumed, which has a journal
+                if let Some(journal) = &self.journal {
+                    journal.skip_file(extracted_file)?;
+                }
+                crc32
             }
+            None => {
+                // Files that didn't change since the previous incremental dump are not written again
+                let unchanged_crc32 = self.incremental.as_ref().filter(|_| shadow_folder.is_none()).and_then(|incremental| {
+                    incremental.unchanged_crc32(decompressor, extracted_file, &self.extract_folder)
+                });
+
+                let crc32 = match unchanged_crc32 {
+                    Some(crc32) => crc32,
+                    None => match &shadow_folder {
+                        Some(shadow_folder) => FolderSink::new(shadow_folder).write_file(decompressor, extracted_file)?,
+                        None => self.writer.write_file(decompressor, extracted_file)?,
+                    }.unwrap_or_default(),
+                };
+
+                match &self.journal {
+                    Some(journal) if shadow_folder.is_some() => journal.skip_file(extracted_file)?,
+                    Some(journal) => journal.record_file(extracted_file, crc32)?,
+                    None => {}
+                }
+                crc32
+            }
+        };
 
+        if let Some(incremental) = &self.incremental && shadow_folder.is_none() {
+            incremental.record(extracted_file, crc32);
         }
-    };
 
-    cpk_files = cpk_files.into_iter().filter(|cpk_file| {
-        let filename = cpk_file.file_name().unwrap().to_str().unwrap();
-        selected_cpk.iter().any(|s| s == filename)
-    }).collect();
+        if let Some(manifest) = &self.manifest {
+            let record = ManifestRecord::new(
+                extracted_file,
+                is_compressed(extracted_file),
+                crc32,
+                decompress_start.elapsed(),
+            );
+            manifest.write(&record)?;
+        }
 
-    (cpk_files, selected_files)
-}
\ No newline at end of file
+        Ok(Some(crc32))
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::{HashMap, HashSet}, fs::{self, File}, io::{self, BufReader, BufWriter}, path::{Path, PathBuf}, sync::Mutex
+};
+
+use serde::{Deserialize, Serialize};
+
+use ievr_toolbox_core::{CpkFile, Decompressor, GameIndex, IndexedCpk, Overrides, crc32, crc32_reader, extracted_crc32};
+
+use crate::{cpk_index::CPK_LIST_NAME, journal::file_key};
+
+/// Bumped whenever the layout of the state changes
+const STATE_VERSION: u32 = 3;
+
+const STATE_NAME: &str = ".dump_state.json";
+
+/// Location of the state of an incremental dump into `extract_folder`
+pub fn dump_state_path(extract_folder: &Path) -> PathBuf {
+    extract_folder.join(STATE_NAME)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CpkState {
+    pub size: u64,
+    pub mtime: u64,
+    pub toc_hash: u32,
+}
+
+impl From<&IndexedCpk> for CpkState {
+    fn from(cpk: &IndexedCpk) -> Self {
+        Self { size: cpk.size, mtime: cpk.mtime, toc_hash: cpk.toc_hash() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileState {
+    /// Path of the CPK relative to the data folder
+    pub cpk: String,
+    /// Offset of the file in its CPK
+    pub offset: u64,
+    pub file_size: u32,
+    pub extract_size: u32,
+    pub crc32: u32,
+}
+
+/// What the game looked like at the time of the last incremental dump,
+/// and what was extracted from it
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DumpState {
+    version: u32,
+    cpk_list_crc32: Option<u32>,
+    /// The CPKs by their path relative to the data folder, as CPKs in different folders may have the same name
+    cpks: HashMap<String, CpkState>,
+    files: HashMap<String, FileState>,
+}
+
+impl DumpState {
+    /// Loads the state of the previous dump. A missing or outdated state is empty,
+    /// which makes every CPK look new.
+    pub fn load(path: &Path) -> DumpState {
+        File::open(path).ok()
+            .and_then(|file| serde_json::from_reader::<_, DumpState>(BufReader::new(file)).ok())
+            .filter(|state| state.version == STATE_VERSION)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn cpk_list_crc32(&self) -> Option<u32> {
+        self.cpk_list_crc32
+    }
+
+    /// Whether the CPK is new or was modified since this state was saved
+    pub fn is_cpk_changed(&self, cpk: &IndexedCpk) -> bool {
+        self.cpks.get(&cpk.relative_path) != Some(&CpkState::from(cpk))
+    }
+
+    /// Files of this state that are no longer in any CPK of the game
+    pub fn removed_files<'a>(&'a self, game_index: &'a GameIndex) -> impl Iterator<Item = &'a str> {
+        self.files.keys()
+            .filter(|path| game_index.get(path).is_none())
+            .map(String::as_str)
+    }
+
+    /// CPKs holding files whose copy loaded by the game is no longer the one this state extracted,
+    /// for example because `cpk_list.cfg.bin` changed. Their files need to be extracted again.
+    pub fn cpks_with_new_winners(&self, game_index: &GameIndex, overrides: &Overrides) -> HashSet<String> {
+        self.files.iter()
+            .filter_map(|(path, file)| {
+                let winner = overrides.resolve(game_index, path)?.cpk.relative_path.clone();
+                (winner != file.cpk).then_some(winner)
+            })
+            .collect()
+    }
+}
+
+/// An incremental dump: the state of the previous dump, and the one being built
+pub struct IncrementalDump {
+    previous: DumpState,
+    /// What the CPKs of the game look like now
+    current_cpks: HashMap<String, CpkState>,
+    extracted: Mutex<HashMap<String, FileState>>,
+}
+
+impl IncrementalDump {
+    pub fn new(previous: DumpState, game_index: &GameIndex) -> Self {
+        let current_cpks = game_index.cpks().iter().map(|cpk| (cpk.relative_path.clone(), CpkState::from(cpk))).collect();
+        Self { previous, current_cpks, extracted: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the CRC32 of the file if the copy extracted by the previous dump
+    /// is identical to the new version, in which case it doesn't need to be written again
+    pub fn unchanged_crc32(&self, decompressor: &mut Decompressor, file: &CpkFile, extract_folder: &Path) -> Option<u32> {
+        let key = file_key(file);
+        let previous = self.previous.files.get(&key)?;
+        if previous.file_size != file.file_size || previous.extract_size != file.extract_size {
+            return None;
+        }
+
+        let path = extract_folder.join(&key);
+        if fs::metadata(&path).ok()?.len() != file.extract_size as u64 {
+            return None;
+        }
+
+        // The same entry of a CPK that didn't change holds the same bytes, so nothing needs to be read
+        let cpk_path = file.cpk_name.as_deref().unwrap_or_default();
+        let same_cpk = previous.cpk == cpk_path
+            && self.previous.cpks.get(cpk_path).is_some_and(|cpk| self.current_cpks.get(cpk_path) == Some(cpk));
+        if same_cpk && previous.offset == file.file_offset {
+            return Some(previous.crc32);
+        }
+
+        // Comparing the new version needs it decompressed in RAM, so files too big for the memory budget are written again
+        if decompressor.low_memory() {
+            return None;
+        }
+
+        let crc32_value = extracted_crc32(decompressor, file).ok()?;
+        if crc32_value != previous.crc32 || crc32_reader(File::open(&path).ok()?).ok()? != crc32_value {
+            return None;
+        }
+
+        Some(crc32_value)
+    }
+
+    pub fn record(&self, file: &CpkFile, crc32: u32) {
+        let state = FileState {
+            cpk: file.cpk_name.as_deref().unwrap_or_default().to_string(),
+            offset: file.file_offset,
+            file_size: file.file_size,
+            extract_size: file.extract_size,
+            crc32,
+        };
+
+        self.extracted.lock().unwrap().insert(file_key(file), state);
+    }
+
+    /// Builds the state after the dump: the files of the CPKs that didn't change
+    /// are carried over from the previous state, the others come from this dump.
+    /// Files that are no longer in the game are kept track of until they are deleted.
+    pub fn finish(self, game_index: &GameIndex, cpk_list_crc32: Option<u32>, removed_deleted: bool) -> DumpState {
+        let unchanged: HashSet<String> = game_index.cpks()
+            .iter()
+            .filter(|cpk| !self.previous.is_cpk_changed(cpk))
+            .map(|cpk| cpk.relative_path.clone())
+            .collect();
+
+        let mut files: HashMap<String, FileState> = self.previous.files
+            .into_iter()
+            .filter(|(path, file)| match game_index.get(path) {
+                Some(_) => unchanged.contains(&file.cpk),
+                None => !removed_deleted,
+            })
+            .collect();
+        files.extend(self.extracted.into_inner().unwrap());
+
+        DumpState {
+            version: STATE_VERSION,
+            cpk_list_crc32,
+            cpks: game_index.cpks().iter().map(|cpk| (cpk.relative_path.clone(), CpkState::from(cpk))).collect(),
+            files,
+        }
+    }
+}
+
+/// CRC32 of `cpk_list.cfg.bin`, if the game has one
+pub fn cpk_list_crc32(game_folder: &Path) -> Option<u32> {
+    fs::read(game_folder.join(CPK_LIST_NAME)).ok().map(|data| crc32(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use ievr_toolbox_core::IndexedFile;
+
+    use super::*;
+
+    const DATA_FOLDER: &str = "game/data";
+
+    fn indexed_cpk(relative_path: &str, mtime: u64, files: &[(&str, u64)]) -> IndexedCpk {
+        IndexedCpk {
+            path: Path::new(DATA_FOLDER).join(relative_path),
+            relative_path: relative_path.to_string(),
+            size: 0x10000,
+            mtime,
+            files: files.iter().map(|&(path, file_offset)| {
+                let (directory, file_name) = path.rsplit_once('/').unwrap();
+                IndexedFile {
+                    directory: directory.to_string(),
+                    file_name: file_name.to_string(),
+                    file_offset,
+                    file_size: 16,
+                    extract_size: 32,
+                    user_string: None,
+                }
+            }).collect(),
+        }
+    }
+
+    fn extracted_file(game_index: &GameIndex, cpk_path: &str, path: &str) -> CpkFile {
+        game_index.files()
+            .find(|found| found.cpk.relative_path == cpk_path && found.file.path() == path)
+            .unwrap()
+            .cpk_file()
+    }
+
+    /// Runs an incremental dump extracting `extracted` from `game_index`, each with its CRC32
+    fn dump(previous: DumpState, game_index: &GameIndex, extracted: &[(&str, &str, u32)], removed_deleted: bool) -> DumpState {
+        let incremental = IncrementalDump::new(previous, game_index);
+        for &(cpk_path, path, crc32) in extracted {
+            incremental.record(&extracted_file(game_index, cpk_path, path), crc32);
+        }
+        incremental.finish(game_index, None, removed_deleted)
+    }
+
+    fn crc32_of(state: &DumpState, path: &str) -> Option<u32> {
+        state.files.get(path).map(|file| file.crc32)
+    }
+
+    fn first_dump() -> (GameIndex, DumpState) {
+        let game_index = GameIndex::new(vec![
+            indexed_cpk("data.cpk", 1, &[("data/common/a.bin", 0x800), ("data/common/b.bin", 0x1000)]),
+            indexed_cpk("dlc/data.cpk", 1, &[("data/dlc/c.bin", 0x800)]),
+        ]);
+        let state = dump(DumpState::default(), &game_index, &[
+            ("data.cpk", "data/common/a.bin", 0xa),
+            ("data.cpk", "data/common/b.bin", 0xb),
+            ("dlc/data.cpk", "data/dlc/c.bin", 0xc),
+        ], false);
+
+        (game_index, state)
+    }
+
+    #[test]
+    fn finish_carries_over_the_files_of_unchanged_cpks() {
+        let (game_index, previous) = first_dump();
+        assert_eq!(previous.files.len(), 3);
+        assert_eq!(previous.files["data/dlc/c.bin"].cpk, "dlc/data.cpk");
+        assert!(game_index.cpks().iter().all(|cpk| !previous.is_cpk_changed(cpk)));
+
+        // Only the CPK with the same name in another folder changed, and only its files are extracted again
+        let game_index = GameIndex::new(vec![
+            indexed_cpk("data.cpk", 1, &[("data/common/a.bin", 0x800), ("data/common/b.bin", 0x1000)]),
+            indexed_cpk("dlc/data.cpk", 2, &[("data/dlc/c.bin", 0x800)]),
+        ]);
+        assert!(!previous.is_cpk_changed(&game_index.cpks()[0]));
+        assert!(previous.is_cpk_changed(&game_index.cpks()[1]));
+
+        let state = dump(previous, &game_index, &[("dlc/data.cpk", "data/dlc/c.bin", 0xc2)], false);
+        assert_eq!(crc32_of(&state, "data/common/a.bin"), Some(0xa));
+        assert_eq!(crc32_of(&state, "data/common/b.bin"), Some(0xb));
+        assert_eq!(crc32_of(&state, "data/dlc/c.bin"), Some(0xc2));
+        assert!(game_index.cpks().iter().all(|cpk| !state.is_cpk_changed(cpk)));
+    }
+
+    #[test]
+    fn files_of_changed_cpks_are_replaced() {
+        let (_, previous) = first_dump();
+
+        // b.bin moved within the changed CPK, and a.bin was left out by this dump
+        let game_index = GameIndex::new(vec![
+            indexed_cpk("data.cpk", 2, &[("data/common/a.bin", 0x800), ("data/common/b.bin", 0x2000)]),
+            indexed_cpk("dlc/data.cpk", 1, &[("data/dlc/c.bin", 0x800)]),
+        ]);
+        let state = dump(previous, &game_index, &[("data.cpk", "data/common/b.bin", 0xb2)], false);
+
+        assert_eq!(crc32_of(&state, "data/common/a.bin"), None);
+        assert_eq!(crc32_of(&state, "data/common/b.bin"), Some(0xb2));
+        assert_eq!(state.files["data/common/b.bin"].offset, 0x2000);
+        assert_eq!(crc32_of(&state, "data/dlc/c.bin"), Some(0xc));
+    }
+
+    #[test]
+    fn removed_files_are_dropped_only_when_removed_deleted() {
+        let (_, previous) = first_dump();
+
+        let game_index = GameIndex::new(vec![
+            indexed_cpk("data.cpk", 2, &[("data/common/a.bin", 0x800)]),
+            indexed_cpk("dlc/data.cpk", 1, &[("data/dlc/c.bin", 0x800)]),
+        ]);
+        assert_eq!(previous.removed_files(&game_index).collect::<Vec<_>>(), ["data/common/b.bin"]);
+
+        let state = dump(previous, &game_index, &[("data.cpk", "data/common/a.bin", 0xa)], false);
+        assert_eq!(crc32_of(&state, "data/common/b.bin"), Some(0xb));
+        assert_eq!(state.removed_files(&game_index).collect::<Vec<_>>(), ["data/common/b.bin"]);
+
+        let state = dump(state, &game_index, &[], true);
+        assert_eq!(crc32_of(&state, "data/common/b.bin"), None);
+        assert_eq!(crc32_of(&state, "data/common/a.bin"), Some(0xa));
+        assert_eq!(state.removed_files(&game_index).count(), 0);
+    }
+
+    #[test]
+    fn cpks_with_new_winners_marks_a_changed_winner() {
+        let (_, previous) = first_dump();
+
+        // A patch in another folder, with the same name, now also holds a.bin
+        let game_index = GameIndex::new(vec![
+            indexed_cpk("data.cpk", 1, &[("data/common/a.bin", 0x800), ("data/common/b.bin", 0x1000)]),
+            indexed_cpk("dlc/data.cpk", 1, &[("data/dlc/c.bin", 0x800)]),
+            indexed_cpk("patch/data.cpk", 1, &[("data/common/a.bin", 0x800)]),
+        ]);
+
+        let overrides = Overrides::new(&game_index, Path::new(DATA_FOLDER), None, &[]);
+        let new_winners = previous.cpks_with_new_winners(&game_index, &overrides);
+        assert_eq!(new_winners, HashSet::from(["patch/data.cpk".to_string()]));
+
+        // The copy extracted before still wins when the priority says so
+        let overrides = Overrides::new(&game_index, Path::new(DATA_FOLDER), None, &["data.cpk".to_string()]);
+        assert!(previous.cpks_with_new_winners(&game_index, &overrides).is_empty());
+
+        // Once extracted from the new winner, the file no longer needs to be extracted again
+        let overrides = Overrides::new(&game_index, Path::new(DATA_FOLDER), None, &[]);
+        let state = dump(previous, &game_index, &[("patch/data.cpk", "data/common/a.bin", 0xa2)], false);
+        assert_eq!(state.files["data/common/a.bin"].cpk, "patch/data.cpk");
+        assert!(state.cpks_with_new_winners(&game_index, &overrides).is_empty());
+    }
+}
@@ -1,10 +1,12 @@
 use std::{fs, path::PathBuf};
 
-use crate::EncryptArgs;
+use ievr_toolbox_core::CancellationToken;
+
+use crate::{EncryptArgs, progress::JsonProgress, report::{Event, Failure, Report}};
 
 const ENCRYPTED_PATH: &str = "encrypted";
 
-pub fn encrypt(args: EncryptArgs) -> std::io::Result<()> {
+pub fn encrypt(args: EncryptArgs, cancellation: &CancellationToken, report: Report) -> std::io::Result<()> {
     let file_path_str = args.input_file.trim_matches('"').trim_end_matches("\\");
 
     let file_path = PathBuf::from(file_path_str);
@@ -23,12 +25,19 @@ pub fn encrypt(args: EncryptArgs) -> std::io::Result<()> {
         fs::create_dir_all(folder)?;
     }
 
-    let result = ievr_toolbox_core::encrypt(&file_path, &output_path);
+    let result = ievr_toolbox_core::encrypt(&file_path, &output_path, cancellation, &JsonProgress::new(report));
 
-    match &result {
-        Ok(()) => println!("File successfully encrypted to {}", output_path.display()),
-        Err(e) => println!("File encryption failed due to {e}"),
-    };
+    // The partial output was removed
+    if cancellation.is_cancelled() && result.is_err() {
+        report.fail(Failure::Cancelled, "File encryption cancelled");
+    }
+
+    if let Err(e) = result {
+        report.fail(Failure::of(&e), format!("File encryption failed due to {e}"));
+    }
+
+    report.text(format!("File successfully encrypted to {}", output_path.display()));
+    report.event(&Event::Encrypted { input: &file_path, output: &output_path });
 
-    result
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::{fs, io::{self, Write}, path::{Path, PathBuf}};
+
+use ievr_toolbox_core::{
+    CpkFile, Decompressor, GameIndex, Overrides, TocParser, find_cpk_files, is_compressed, normalize_path,
+    read_cpk_file, read_cpk_toc, relative_cpk_path,
+};
+
+use crate::{
+    ExtractArgs,
+    cpk_index::{game_index_cache_path, load_cpk_index},
+    game_folder::game_data_folder,
+    report::{Event, Report},
+};
+
+pub fn extract(args: ExtractArgs, report: Report) -> io::Result<()> {
+    // The events already go to the standard output
+    if report.json() && output_path(&args.output_file).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--json needs an output file given with -o, the standard output holds the events",
+        ));
+    }
+
+    let game_folder = game_data_folder(&args.input_folder)?;
+
+    let requested_path = normalize_path(&args.path);
+    let (directory, file_name) = match requested_path.rsplit_once('/') {
+        Some((directory, file_name)) => (directory, file_name),
+        None => ("", requested_path.as_str()),
+    };
+
+    // cpk_list.cfg.bin tells us which CPK to look into without reading every TOC
+    let cpk_index = load_cpk_index(&game_folder).unwrap_or_else(|e| {
+        report.warning(&format!("Unable to use cpk_list.cfg.bin ({e}), looking the file up in the CPKs' TOC instead"));
+        None
+    });
+
+    // The listed CPK is the copy the game loads, as long as it is the only CPK of the list holding the file
+    if let Some(cpk_index) = &cpk_index {
+        let cpk_files = find_cpk_files(&game_folder)?;
+        let candidates = cpk_files.iter().filter(|cpk_file| {
+            let cpk_path = relative_cpk_path(&game_folder, cpk_file);
+            let cpk_name = cpk_file.file_name().unwrap().to_string_lossy();
+            cpk_index.find(file_name).any(|entry| {
+                let listed_cpk = normalize_path(&entry.cpk_name);
+                (listed_cpk == cpk_path || listed_cpk == cpk_name)
+                    && entry.directory.as_deref().is_none_or(|entry_directory| entry_directory == directory)
+            })
+        });
+
+        let mut toc_parser = TocParser::default();
+        let mut listed = Vec::new();
+
+        for cpk_path in candidates {
+            let toc = read_cpk_toc(cpk_path, &mut toc_parser)?;
+
+            let found = toc.into_iter().find(|file| {
+                file.file_name == file_name
+                    && normalize_path(file.directory.as_deref().unwrap_or_default()) == directory
+            });
+
+            if let Some(file) = found {
+                listed.push((cpk_path, file));
+            }
+        }
+
+        if let [(cpk_path, file)] = listed.as_slice() {
+            return extract_file(cpk_path, file, &requested_path, &args.output_file, report);
+        }
+    }
+
+    // Otherwise we look it up in the index of every CPK's TOC, and pick the copy loaded by the game like a dump
+    let game_index = GameIndex::load(&game_folder, Some(&game_index_cache_path(&game_folder)))?;
+    let overrides = Overrides::new(&game_index, &game_folder, cpk_index.as_ref(), &[]);
+    if let Some(found) = overrides.resolve(&game_index, &requested_path) {
+        return extract_file(&found.cpk.path, &found.cpk_file(), &requested_path, &args.output_file, report);
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("{requested_path} was not found in any CPK.")))
+}
+
+/// The file to write to, or `None` for the standard output
+fn output_path(output_file: &str) -> Option<PathBuf> {
+    let output_file = output_file.trim_matches('"').trim_end_matches("\\");
+    (!output_file.is_empty() && output_file != "-").then(|| PathBuf::from(output_file))
+}
+
+fn extract_file(cpk_path: &Path, file: &CpkFile, requested_path: &str, output_file: &str, report: Report) -> io::Result<()> {
+    // Messages go to stderr, as the file itself may be written to the standard output
+    if !report.json() {
+        eprintln!("Found {} in {}", requested_path, cpk_path.display());
+    }
+
+    let file = read_cpk_file(cpk_path, file)?;
+    let Some(output_path) = output_path(output_file) else {
+        return write_stdout(&file);
+    };
+
+    write_output(&file, &output_path)?;
+    if !report.json() {
+        eprintln!("File successfully extracted to {}", output_path.display());
+    }
+    report.event(&Event::Extracted { path: requested_path, cpk: cpk_path, output: &output_path });
+    Ok(())
+}
+
+fn write_stdout(file: &CpkFile) -> io::Result<()> {
+    let mut decompressor = Decompressor::default();
+    let mut stdout = io::stdout().lock();
+    if is_compressed(file) {
+        stdout.write_all(&decompressor.decompress_to_vec(file)?)?;
+    } else {
+        stdout.write_all(file.data().unwrap())?;
+    }
+    stdout.flush()
+}
+
+fn write_output(file: &CpkFile, output_path: &Path) -> io::Result<()> {
+    let mut decompressor = Decompressor::default();
+    if let Some(folder) = output_path.parent() {
+        fs::create_dir_all(folder)?;
+    }
+
+    if is_compressed(file) {
+        decompressor.decompress(&output_path.to_path_buf(), file)?;
+    } else {
+        fs::write(output_path, file.data().unwrap())?;
+    }
+
+    Ok(())
+}
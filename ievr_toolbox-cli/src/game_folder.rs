@@ -0,0 +1,22 @@
+use std::{io, path::PathBuf};
+
+/// Resolves the `data` folder of the game from the path given by the user,
+/// failing if it does not exist
+pub fn game_data_folder(input_folder: &str) -> io::Result<PathBuf> {
+    let game_path = input_folder.trim_matches('"').trim_end_matches("\\"); // This removes all quotes and trailing backslashes
+
+    let mut game_folder = PathBuf::from(game_path);
+
+    if !game_folder.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The path {} does not exist.", game_folder.display()),
+        ));
+    }
+
+    if !game_folder.ends_with("data") {
+        game_folder.push("data");
+    }
+
+    Ok(game_folder)
+}
@@ -0,0 +1,80 @@
+use std::{fs, io, path::PathBuf};
+
+use ievr_toolbox_core::{CancellationToken, DecryptCache, DecryptionPath, IoThrottle, TableLocation, TocParser, cpk_info, try_decrypt_cpk};
+
+use crate::{GB, MB, TMP_PATH, InfoArgs, report::{Event, Report}};
+
+pub fn info(args: InfoArgs, report: Report) -> io::Result<()> {
+    let file_path_str = args.input_file.trim_matches('"').trim_end_matches("\\");
+
+    let file_path = PathBuf::from(file_path_str);
+
+    if !file_path.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("The file {} does not exist.", file_path.display()),
+        ));
+    }
+
+    // Big CPKs are decrypted to the temp folder, where the folders may have to be created
+    let temp_folder = PathBuf::from(TMP_PATH);
+    let decrypt_cache = DecryptCache::new(&temp_folder);
+    let created_folders: Vec<PathBuf> = [decrypt_cache.folder(), temp_folder.as_path()]
+        .into_iter()
+        .filter(|folder| !folder.exists())
+        .map(PathBuf::from)
+        .collect();
+
+    let (decrypted_cpk, decryption) = try_decrypt_cpk(&file_path, &temp_folder, GB, &CancellationToken::new(), &IoThrottle::unlimited())?;
+
+    let mut toc_parser = TocParser::default();
+    let info = cpk_info(decrypted_cpk, &mut toc_parser);
+
+    // The copy is removed even if the CPK can't be read. Only the copy decrypted here is removed, a copy cached by a dump is kept for the next ones
+    if decryption == DecryptionPath::Temp {
+        decrypt_cache.remove(&file_path)?;
+    }
+    for folder in created_folders {
+        let _ = fs::remove_dir(folder);
+    }
+    let info = info?;
+
+    if report.json() {
+        report.event(&Event::CpkInfo { path: &file_path, info: &info });
+        return Ok(());
+    }
+
+    let header = &info.header;
+
+    println!("--- {} ---", file_path.display());
+    println!("Size: {:.2} MiB", info.cpk_size as f64 / MB as f64);
+    println!("Version: {} (revision {})", display_number(header.version), display_number(header.revision));
+    println!("Alignment: {}", display_number(header.alignment));
+    println!("Codec: {}", display_number(header.codec));
+    println!("Masked tables: {}", if info.masked { "yes" } else { "no" });
+
+    println!("\n--- Tables ---");
+    println!("Content: {}", display_location(header.content));
+    println!("TOC:     {}", display_location(header.toc));
+    println!("ITOC:    {}", display_location(header.itoc));
+    println!("ETOC:    {}", display_location(header.etoc));
+    println!("GTOC:    {}", display_location(header.gtoc));
+
+    println!("\n--- Files ---");
+    println!("Files: {} ({} CRILAYLA-compressed)", info.file_count, info.compressed_count);
+    println!("Stored size: {:.2} MiB", info.stored_size as f64 / MB as f64);
+    println!("Extracted size: {:.2} MiB", info.extracted_size as f64 / MB as f64);
+
+    Ok(())
+}
+
+fn display_number(value: Option<u64>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+fn display_location(location: Option<TableLocation>) -> String {
+    match location {
+        Some(location) => format!("offset {:#010x}, size {} bytes", location.offset, location.size),
+        None => "-".to_string(),
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::{HashMap, HashSet}, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, LineWriter, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}
+};
+
+use ievr_toolbox_core::{CpkFile, crc32, normalize_path};
+
+const JOURNAL_NAME: &str = ".dump_journal";
+
+/// Location of the journal of a dump into `extract_folder`
+pub fn journal_path(extract_folder: &Path) -> PathBuf {
+    extract_folder.join(JOURNAL_NAME)
+}
+
+/// Path of the file in the game, used to identify it across dumps
+pub fn file_key(file: &CpkFile) -> String {
+    let directory = normalize_path(file.directory.as_deref().unwrap_or_default());
+    if directory.is_empty() {
+        file.file_name.clone()
+    } else {
+        format!("{directory}/{}", file.file_name)
+    }
+}
+
+/// What a previous dump recorded in its journal
+#[derive(Debug, Default)]
+pub struct PreviousDump {
+    /// The CPKs done, by their path relative to the data folder
+    cpks: HashSet<String>,
+    files: HashMap<String, (u32, u32)>,
+}
+
+impl PreviousDump {
+    /// Reads the journal. The last line may have been cut if the dump was killed,
+    /// so lines that can't be parsed are ignored.
+    pub fn load(path: &Path) -> io::Result<PreviousDump> {
+        let mut previous = PreviousDump::default();
+        if !path.exists() {
+            return Ok(previous);
+        }
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+
+            match fields.as_slice() {
+                ["cpk", cpk_path] => {
+                    previous.cpks.insert(cpk_path.to_string());
+                }
+                ["file", path, size, crc32] => {
+                    if let (Ok(size), Ok(crc32)) = (size.parse(), u32::from_str_radix(crc32, 16)) {
+                        previous.files.insert(path.to_string(), (size, crc32));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(previous)
+    }
+
+    /// Whether every selected file of the CPK at `cpk_path`, relative to the data folder, was extracted
+    pub fn is_cpk_done(&self, cpk_path: &str) -> bool {
+        self.cpks.contains(cpk_path)
+    }
+
+    /// Returns the CRC32 of the file if it was already extracted and the copy
+    /// in `extract_folder` still has the recorded size and hash
+    pub fn extracted_crc32(&self, file: &CpkFile, extract_folder: &Path) -> Option<u32> {
+        let key = file_key(file);
+        let &(size, crc32_value) = self.files.get(&key)?;
+        if size != file.extract_size {
+            return None;
+        }
+
+        let path = extract_folder.join(&key);
+        if fs::metadata(&path).ok()?.len() != size as u64 {
+            return None;
+        }
+
+        (crc32(&fs::read(&path).ok()?) == crc32_value).then_some(crc32_value)
+    }
+}
+
+struct State {
+    writer: LineWriter<File>,
+    remaining: HashMap<Arc<str>, usize>,
+}
+
+/// Thread-safe journal of the CPKs and files completed by a dump. Every line is
+/// written as soon as it is complete so that the journal survives the program being killed.
+#[derive(Clone)]
+pub struct Journal {
+    inner: Arc<Mutex<State>>,
+}
+
+impl Journal {
+    /// Opens the journal, starting a new one unless `resume` is set
+    pub fn open(path: &Path, resume: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(path)?;
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(State {
+                writer: LineWriter::new(file),
+                remaining: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Registers how many files will be extracted from the CPK at `cpk_path`, relative to the data folder
+    pub fn expect(&self, cpk_path: &Arc<str>, file_count: usize) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+
+        if file_count == 0 {
+            writeln!(state.writer, "cpk\t{cpk_path}")
+        } else {
+            state.remaining.insert(cpk_path.clone(), file_count);
+            Ok(())
+        }
+    }
+
+    /// Records a file that was just extracted
+    pub fn record_file(&self, file: &CpkFile, crc32: u32) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+
+        writeln!(state.writer, "file\t{}\t{}\t{crc32:08x}", file_key(file), file.extract_size)?;
+        Self::complete(&mut state, file)
+    }
+
+    /// Marks a file that was already extracted by a previous dump as done
+    pub fn skip_file(&self, file: &CpkFile) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+
+        Self::complete(&mut state, file)
+    }
+
+    fn complete(state: &mut State, file: &CpkFile) -> io::Result<()> {
+        let Some(cpk_name) = &file.cpk_name else {
+            return Ok(());
+        };
+
+        let Some(remaining) = state.remaining.get_mut(cpk_name) else {
+            return Ok(());
+        };
+
+        *remaining -= 1;
+        if *remaining == 0 {
+            state.remaining.remove(cpk_name);
+            writeln!(state.writer, "cpk\t{cpk_name}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("ievr_toolbox_{}_{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn extracted_file(cpk_path: &str, directory: &str, file_name: &str, extract_size: u32) -> CpkFile {
+        let mut file = CpkFile::default();
+        file.cpk_name = Some(Arc::from(cpk_path));
+        file.directory = Some(Arc::from(directory));
+        file.file_name = file_name.to_string();
+        file.file_size = extract_size;
+        file.extract_size = extract_size;
+        file
+    }
+
+    #[test]
+    fn load_reads_back_a_journal_written_by_a_run() {
+        let folder = temp_folder("journal_round_trip");
+        let path = journal_path(&folder);
+
+        let journal = Journal::open(&path, false).unwrap();
+        let (done, empty, partial) = (Arc::from("data.cpk"), Arc::from("empty.cpk"), Arc::from("dlc/data.cpk"));
+        journal.expect(&done, 2).unwrap();
+        journal.expect(&empty, 0).unwrap();
+        journal.expect(&partial, 2).unwrap();
+        journal.record_file(&extracted_file("data.cpk", "data/common", "a.bin", 3), 0xa).unwrap();
+        journal.skip_file(&extracted_file("data.cpk", "data/common", "b.bin", 4)).unwrap();
+        journal.record_file(&extracted_file("dlc/data.cpk", "data/dlc", "c.bin", 5), 0xc).unwrap();
+        drop(journal);
+
+        let previous = PreviousDump::load(&path).unwrap();
+        assert!(previous.is_cpk_done("data.cpk"));
+        assert!(previous.is_cpk_done("empty.cpk"));
+        assert!(!previous.is_cpk_done("dlc/data.cpk"));
+        // CPKs with the same name in another folder are told apart
+        assert!(!previous.is_cpk_done("dlc/empty.cpk"));
+        assert_eq!(previous.files.get("data/common/a.bin"), Some(&(3, 0xa)));
+        assert_eq!(previous.files.get("data/dlc/c.bin"), Some(&(5, 0xc)));
+        // A file skipped was recorded by the run that extracted it
+        assert_eq!(previous.files.get("data/common/b.bin"), None);
+
+        // Resuming appends to the journal, which completes the CPK left halfway
+        let journal = Journal::open(&path, true).unwrap();
+        journal.expect(&partial, 1).unwrap();
+        journal.record_file(&extracted_file("dlc/data.cpk", "data/dlc", "d.bin", 6), 0xd).unwrap();
+        drop(journal);
+
+        let previous = PreviousDump::load(&path).unwrap();
+        assert!(previous.is_cpk_done("data.cpk"));
+        assert!(previous.is_cpk_done("dlc/data.cpk"));
+        assert_eq!(previous.files.len(), 3);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn truncated_last_line_is_ignored() {
+        let folder = temp_folder("journal_truncated");
+        let path = journal_path(&folder);
+        fs::write(&path, "file\tdata/a.bin\t3\t0000000a\ncpk\tdata.cpk\nfile\tdata/b.bin\t4").unwrap();
+
+        let previous = PreviousDump::load(&path).unwrap();
+        assert!(previous.is_cpk_done("data.cpk"));
+        assert_eq!(previous.files.get("data/a.bin"), Some(&(3, 0xa)));
+        assert_eq!(previous.files.get("data/b.bin"), None);
+
+        // A line cut in the middle of a number isn't recorded either
+        fs::write(&path, "file\tdata/a.bin\t3\t0000000a\nfile\tdata/b.bin\t4\tzz").unwrap();
+        let previous = PreviousDump::load(&path).unwrap();
+        assert_eq!(previous.files.len(), 1);
+
+        // Without a journal, nothing was done
+        let previous = PreviousDump::load(&folder.join("missing")).unwrap();
+        assert!(!previous.is_cpk_done("data.cpk"));
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn extracted_crc32_checks_the_file_left_by_the_previous_run() {
+        let folder = temp_folder("journal_extracted");
+        let path = journal_path(&folder);
+        let content = b"chara";
+        let file = extracted_file("data.cpk", "data/common", "chara_param.cfg.bin", content.len() as u32);
+
+        let journal = Journal::open(&path, false).unwrap();
+        journal.record_file(&file, crc32(content)).unwrap();
+        drop(journal);
+        let previous = PreviousDump::load(&path).unwrap();
+
+        // Not written yet
+        assert_eq!(previous.extracted_crc32(&file, &folder), None);
+
+        let extracted_path = folder.join("data/common/chara_param.cfg.bin");
+        fs::create_dir_all(extracted_path.parent().unwrap()).unwrap();
+        fs::write(&extracted_path, content).unwrap();
+        assert_eq!(previous.extracted_crc32(&file, &folder), Some(crc32(content)));
+
+        // The file changed in the game since the previous run
+        let resized = extracted_file("data.cpk", "data/common", "chara_param.cfg.bin", 6);
+        assert_eq!(previous.extracted_crc32(&resized, &folder), None);
+
+        // The copy in the output folder was modified, or cut short
+        fs::write(&extracted_path, b"CHARA").unwrap();
+        assert_eq!(previous.extracted_crc32(&file, &folder), None);
+        fs::write(&extracted_path, b"cha").unwrap();
+        assert_eq!(previous.extracted_crc32(&file, &folder), None);
+
+        // A file never extracted isn't skipped
+        let other = extracted_file("data.cpk", "data/common", "other.bin", 5);
+        assert_eq!(previous.extracted_crc32(&other, &folder), None);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
@@ -1,10 +1,27 @@
+use std::process::exit;
+
 use clap::Parser;
+use ievr_toolbox_core::CancellationToken;
 
-mod memory_budget;
+mod disk_space;
 mod args;
 mod dump;
 mod decrypt;
 mod encrypt;
+mod info;
+mod manifest;
+mod journal;
+mod dump_state;
+mod extract;
+mod game_folder;
+mod cpk_index;
+mod which;
+mod diff;
+mod preflight;
+mod priority;
+mod progress;
+mod performance;
+mod report;
 
 use args::{
     Args,
@@ -15,24 +32,56 @@ pub use crate::{
     args::DumpArgs,
     args::DecryptArgs,
     args::EncryptArgs,
+    args::InfoArgs,
+    args::ExtractArgs,
+    args::WhichArgs,
+    args::DiffArgs,
 };
 
 use dump::dump;
 use decrypt::decrypt;
 use encrypt::encrypt;
+use info::info;
+use extract::extract;
+use which::which;
+use diff::diff;
+use report::{Failure, Report};
 
 const TMP_PATH: &str = "temp";
+const CACHE_PATH: &str = "cache";
 
 const MB: usize = 1024 * 1024;
 const GB: usize = 1024 * MB;
 
-fn main() -> std::io::Result<()> {
+/// Exit code of a command stopped by Ctrl-C, as if it was killed by SIGINT
+const CANCELLED_EXIT_CODE: i32 = 130;
+
+fn main() {
     let args = Args::parse();
+    let report = Report::new(args.json);
+
+    // The first Ctrl-C lets the command stop cleanly, the second one exits right away
+    let cancellation = CancellationToken::new();
+    let handler_token = cancellation.clone();
+    ctrlc::set_handler(move || {
+        if handler_token.is_cancelled() {
+            exit(CANCELLED_EXIT_CODE);
+        }
+        eprintln!("\nStopping, press Ctrl-C again to exit right away...");
+        handler_token.cancel();
+    }).expect("Unable to set the Ctrl-C handler");
+
+    let result = match args.command {
+        Command::Dump(dump_args) => dump(dump_args, &cancellation, report),
+        Command::Decrypt(decrypt_args) => decrypt(decrypt_args, &cancellation, report),
+        Command::Encrypt(encrypt_args) => encrypt(encrypt_args, &cancellation, report),
+        Command::Info(info_args) => info(info_args, report),
+        Command::Extract(extract_args) => extract(extract_args, report),
+        Command::Which(which_args) => which(which_args, report),
+        Command::Diff(diff_args) => diff(diff_args, report),
+    };
 
-    match args.command {
-        Command::Dump(dump_args) => dump(dump_args),
-        Command::Decrypt(decrypt_args) => decrypt(decrypt_args),
-        Command::Encrypt(encrypt_args) => encrypt(encrypt_args),
+    if let Err(e) = result {
+        report.fail(Failure::of(&e), e);
     }
-    
 }
\ No newline at end of file
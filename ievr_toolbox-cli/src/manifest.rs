@@ -0,0 +1,135 @@
+use std::{
+    fs::File, io::{self, BufWriter, Write}, path::Path, sync::{Arc, Mutex}, time::Duration
+};
+
+use serde::Serialize;
+
+use ievr_toolbox_core::CpkFile;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ManifestFormat {
+    Json,
+    Csv,
+}
+
+/// One line of the manifest, describing where an extracted file came from
+#[derive(Debug, Serialize)]
+pub struct ManifestRecord<'a> {
+    pub cpk: &'a str,
+    pub directory: &'a str,
+    pub file_name: &'a str,
+    pub file_offset: u64,
+    pub file_size: u32,
+    pub extract_size: u32,
+    pub compressed: bool,
+    pub user_string: &'a str,
+    pub crc32: String,
+    pub decompress_time_ms: f64,
+}
+
+impl<'a> ManifestRecord<'a> {
+    pub fn new(file: &'a CpkFile, compressed: bool, crc32: u32, decompress_time: Duration) -> Self {
+        Self {
+            cpk: file.cpk_name.as_deref().unwrap_or_default(),
+            directory: file.directory.as_deref().unwrap_or_default(),
+            file_name: &file.file_name,
+            file_offset: file.file_offset,
+            file_size: file.file_size,
+            extract_size: file.extract_size,
+            compressed,
+            user_string: file.user_string.as_deref().unwrap_or_default(),
+            crc32: format!("{crc32:08x}"),
+            decompress_time_ms: decompress_time.as_secs_f64() * 1000.0,
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{:.3}\n",
+            csv_field(self.cpk),
+            csv_field(self.directory),
+            csv_field(self.file_name),
+            self.file_offset,
+            self.file_size,
+            self.extract_size,
+            self.compressed,
+            csv_field(self.user_string),
+            self.crc32,
+            self.decompress_time_ms,
+        )
+    }
+}
+
+const CSV_HEADER: &str = "cpk,directory,file_name,file_offset,file_size,extract_size,compressed,user_string,crc32,decompress_time_ms\n";
+
+struct State {
+    writer: BufWriter<File>,
+    records: usize,
+}
+
+/// Thread-safe manifest writer shared by the decompression workers.
+/// The format is picked from the extension: `.json` writes a JSON array,
+/// anything else writes CSV.
+#[derive(Clone)]
+pub struct ManifestWriter {
+    inner: Arc<Mutex<State>>,
+    format: ManifestFormat,
+}
+
+impl ManifestWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let format = match path.extension() {
+            Some(ext) if ext.to_string_lossy().to_lowercase() == "json" => ManifestFormat::Json,
+            _ => ManifestFormat::Csv,
+        };
+
+        if let Some(folder) = path.parent() {
+            std::fs::create_dir_all(folder)?;
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        match format {
+            ManifestFormat::Json => writer.write_all(b"[")?,
+            ManifestFormat::Csv => writer.write_all(CSV_HEADER.as_bytes())?,
+        }
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(State { writer, records: 0 })),
+            format,
+        })
+    }
+
+    pub fn write(&self, record: &ManifestRecord) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+
+        match self.format {
+            ManifestFormat::Json => {
+                let separator = if state.records == 0 { "\n  " } else { ",\n  " };
+                state.writer.write_all(separator.as_bytes())?;
+                serde_json::to_writer(&mut state.writer, record)?;
+            }
+            ManifestFormat::Csv => state.writer.write_all(record.to_csv().as_bytes())?,
+        }
+
+        state.records += 1;
+        Ok(())
+    }
+
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+
+        if self.format == ManifestFormat::Json {
+            state.writer.write_all(b"\n]\n")?;
+        }
+
+        state.writer.flush()
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,73 @@
+use std::{fs, io, path::Path};
+
+use ievr_toolbox_core::{DecryptionPath, PerformanceReport};
+
+use crate::{MB, preflight::format_size};
+
+/// Prints where the time of the dump went, for every stage and every CPK
+pub fn print_performance(report: &PerformanceReport) {
+    let count = |decryption: DecryptionPath| report.cpks.iter().filter(|cpk| cpk.decryption == decryption).count();
+    let file_count: usize = report.cpks.iter().map(|cpk| cpk.file_count).sum();
+    let memory = &report.memory;
+
+    println!("\n--- Performance ---");
+    println!("Times are summed over the threads\n");
+    println!(
+        "Decryption: {} CPKs ({} in RAM, {} to the temp folder, {} already decrypted) - {} in {:.2?}, {}",
+        report.cpks.len(),
+        count(DecryptionPath::Ram),
+        count(DecryptionPath::Temp),
+        count(DecryptionPath::Cached),
+        format_size(report.decryption.bytes_read),
+        report.decryption.time,
+        format_throughput(report.decryption.throughput()),
+    );
+    println!(
+        "Decompression: {} files - {} read, {} written in {:.2?}, {}",
+        file_count,
+        format_size(report.decompression.bytes_read),
+        format_size(report.decompression.bytes_written),
+        report.decompression.time,
+        format_throughput(report.decompression.throughput()),
+    );
+    println!(
+        "Memory: {} files decompressed through a small buffer because the memory was taken - RAM held back for decompression {} times",
+        memory.decompressions_without_memory,
+        memory.held_back_for_decompression,
+    );
+    println!(
+        "CPKs put off because the memory was full: {} times, the disk was full: {} times, too many CPKs were open: {} times",
+        memory.memory_full,
+        memory.disk_full,
+        memory.open_cpks_full,
+    );
+
+    println!("\nCPK\tSize\tDecryption\tDecrypt time\tFiles\tExtracted\tDecompress time");
+    for cpk in &report.cpks {
+        // A cached copy is only checked, which says nothing about the decryption speed
+        let (decryption, decrypt_throughput) = match cpk.decryption {
+            DecryptionPath::Ram => ("RAM", format!(" ({})", format_throughput(cpk.decrypt_throughput()))),
+            DecryptionPath::Temp => ("temp folder", format!(" ({})", format_throughput(cpk.decrypt_throughput()))),
+            DecryptionPath::Cached => ("cached", String::new()),
+        };
+        println!(
+            "{}\t{}\t{decryption}\t{:.2?}{decrypt_throughput}\t{}\t{}\t{:.2?} ({})",
+            cpk.name,
+            format_size(cpk.size),
+            cpk.decrypt_time,
+            cpk.file_count,
+            format_size(cpk.extracted_size),
+            cpk.decompress_time,
+            format_throughput(cpk.decompress_throughput()),
+        );
+    }
+}
+
+/// Writes the report as JSON to `path`
+pub fn write_performance(report: &PerformanceReport, path: &Path) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(report)?)
+}
+
+fn format_throughput(bytes_per_second: f64) -> String {
+    format!("{:.2} MiB/s", bytes_per_second / MB as f64)
+}
@@ -0,0 +1,321 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use serde::Serialize;
+
+use ievr_toolbox_core::{BlobSink, DecryptCache, FileSelection, GameIndex, GameIndexMatch, Overrides, TarSink, ZipSink};
+
+use crate::{GB, MB, args::{DumpArgs, OutputFormat, ZipCompressionArg}, disk_space::{Disk, disk_of}, dump::archive_path, report::Event};
+
+/// A CPK of the dump and what will be extracted from it
+#[derive(Debug, Serialize)]
+pub struct PlannedCpk {
+    pub name: String,
+    pub size: u64,
+    pub file_count: usize,
+    /// Size of the selected files once extracted
+    pub output_size: u64,
+    /// Space the selected files take on top of the copies already in the output folder,
+    /// or in the archive with their headers
+    pub output_needed: u64,
+    /// Same as `output_needed` for the overridden copies going to the shadow folder
+    pub shadow_needed: u64,
+    /// Whether the CPK is too big to be decrypted in RAM
+    pub on_disk: bool,
+    /// Whether a valid decrypted copy is already in the temp folder
+    pub cached: bool,
+}
+
+/// The space a dump needs on one disk
+#[derive(Debug, Serialize)]
+pub struct DiskUsage {
+    #[serde(flatten)]
+    pub disk: Disk,
+    pub needed: u64,
+    pub folders: Vec<&'static str>,
+}
+
+impl DiskUsage {
+    pub fn fits(&self) -> bool {
+        self.needed <= self.disk.available
+    }
+}
+
+/// What a dump is going to do, computed from the TOCs before anything is written
+#[derive(Debug)]
+pub struct DumpPlan {
+    pub cpks: Vec<PlannedCpk>,
+    /// Where the files are written: the output folder, or the archive
+    output_path: PathBuf,
+    /// Space taken by the end of the archive
+    archive_end: u64,
+    /// Size of the archive replaced by the dump
+    replaced_size: u64,
+    /// Most the spill files of an archive take in the temp folder at once
+    spill_peak: u64,
+}
+
+impl DumpPlan {
+    /// Plans the extraction of the CPKs at `cpk_paths`, with the same selection as the extractor thread.
+    /// An archive is written by `threads` workers, which may each decompress a file to the temp folder.
+    pub fn new(
+        game_index: &GameIndex,
+        cpk_paths: &[PathBuf],
+        selection: Option<&FileSelection>,
+        overrides: &Overrides,
+        args: &DumpArgs,
+        size_threshold: usize,
+        threads: usize,
+    ) -> DumpPlan {
+        let decrypt_cache = DecryptCache::new(&args.temp_folder);
+        let archive_path = archive_path(args);
+
+        // Any compressed file may be decompressed to a spill file when the memory is taken
+        let mut spilled_sizes = Vec::new();
+
+        let cpks = cpk_paths.iter().map(|cpk_path| {
+            let size = fs::metadata(cpk_path).map(|metadata| metadata.len()).unwrap_or_default();
+            let on_disk = size as usize >= size_threshold;
+
+            let mut planned = PlannedCpk {
+                name: cpk_path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                size,
+                file_count: 0,
+                output_size: 0,
+                output_needed: 0,
+                shadow_needed: 0,
+                on_disk,
+                cached: on_disk && decrypt_cache.contains(cpk_path),
+            };
+
+            let Some(indexed) = game_index.cpks().iter().find(|cpk| cpk.path == *cpk_path) else {
+                return planned;
+            };
+            planned.name = indexed.relative_path.clone();
+
+            for file in &indexed.files {
+                let found = GameIndexMatch { cpk: indexed, file };
+                let file = found.cpk_file();
+                if selection.is_some_and(|selection| !selection.contains_file(&file)) {
+                    continue;
+                }
+
+                let extract_size = file.extract_size as u64;
+                let path = Path::new(&found.file.directory).join(&found.file.file_name);
+
+                if overrides.is_overridden(&file) {
+                    let Some(shadow_folder) = &args.shadow_folder else {
+                        continue;
+                    };
+                    planned.shadow_needed += needed_size(&shadow_folder.join(&planned.name).join(&path), extract_size);
+                } else if archive_path.is_some() {
+                    planned.output_needed += archived_size(args.output_format, &found.file.path(), extract_size);
+                    if file.file_size < file.extract_size {
+                        spilled_sizes.push(extract_size);
+                    }
+                } else {
+                    planned.output_needed += needed_size(&args.output_folder.join(&path), extract_size);
+                }
+
+                planned.file_count += 1;
+                planned.output_size += extract_size;
+            }
+
+            planned
+        }).collect();
+
+        spilled_sizes.sort_unstable_by(|a, b| b.cmp(a));
+        let mut spill_peak: u64 = spilled_sizes.iter().take(threads).sum();
+        // A deflated file is written to a second spill file before being copied to the archive
+        if args.output_format == OutputFormat::Zip && args.zip_compression == ZipCompressionArg::Deflate {
+            spill_peak *= 2;
+        }
+
+        let (archive_end, replaced_size) = match &archive_path {
+            Some(archive_path) => (
+                archive_end_size(args.output_format),
+                fs::metadata(archive_path).map(|metadata| metadata.len()).unwrap_or_default(),
+            ),
+            None => (0, 0),
+        };
+
+        DumpPlan {
+            cpks,
+            output_path: archive_path.unwrap_or_else(|| args.output_folder.clone()),
+            archive_end,
+            replaced_size,
+            spill_peak,
+        }
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.cpks.iter().map(|cpk| cpk.file_count).sum()
+    }
+
+    pub fn output_size(&self) -> u64 {
+        self.cpks.iter().map(|cpk| cpk.output_size).sum()
+    }
+
+    /// Space the output takes on top of what is already there. An archive replaces the one left by a previous dump.
+    pub fn output_needed(&self) -> u64 {
+        let needed: u64 = self.cpks.iter().map(|cpk| cpk.output_needed).sum();
+        (needed + self.archive_end).saturating_sub(self.replaced_size)
+    }
+
+    pub fn shadow_needed(&self) -> u64 {
+        self.cpks.iter().map(|cpk| cpk.shadow_needed).sum()
+    }
+
+    /// The biggest CPK that has to be decrypted to the temp folder
+    pub fn largest_on_disk(&self) -> u64 {
+        self.decrypted_on_disk().max().unwrap_or_default()
+    }
+
+    /// Most the spill files of an archive take in the temp folder at once
+    pub fn spill_peak(&self) -> u64 {
+        self.spill_peak
+    }
+
+    /// Most the temp folder holds at once when the decrypted CPKs may use `disk_budget` bytes, spill files included.
+    /// Cached copies are already there, and the copies kept for the cache stay within the budget.
+    pub fn temp_peak(&self, disk_budget: u64) -> u64 {
+        let total: u64 = self.decrypted_on_disk().sum();
+        total.min(disk_budget).max(self.largest_on_disk()) + self.spill_peak
+    }
+
+    /// Space the extracted files take on `disk`, in the output folder or the archive
+    pub fn output_needed_on(&self, disk: &Disk, args: &DumpArgs) -> u64 {
+        let is_on_disk = |path: &Path| disk_of(path).is_some_and(|other| other.mount_point == disk.mount_point);
+
+        let mut needed = 0;
+        if is_on_disk(&self.output_path) {
+            needed += self.output_needed();
+        }
+        if args.shadow_folder.as_deref().is_some_and(is_on_disk) {
+            needed += self.shadow_needed();
+        }
+        needed
+    }
+
+    /// The space needed on each disk used by the dump. Disks whose free space
+    /// can't be found are left out.
+    pub fn disk_usage(&self, args: &DumpArgs, disk_budget: u64) -> Vec<DiskUsage> {
+        let mut needs = vec![("output", &self.output_path, self.output_needed())];
+        if let Some(shadow_folder) = &args.shadow_folder {
+            needs.push(("shadow", shadow_folder, self.shadow_needed()));
+        }
+        needs.push(("temp", &args.temp_folder, self.temp_peak(disk_budget)));
+
+        let mut usage: Vec<DiskUsage> = Vec::new();
+        for (folder, path, needed) in needs {
+            let Some(disk) = disk_of(path) else {
+                continue;
+            };
+
+            match usage.iter_mut().find(|usage| usage.disk.mount_point == disk.mount_point) {
+                Some(usage) => {
+                    usage.needed += needed;
+                    usage.folders.push(folder);
+                }
+                None => usage.push(DiskUsage { disk, needed, folders: vec![folder] }),
+            }
+        }
+
+        usage
+    }
+
+    fn decrypted_on_disk(&self) -> impl Iterator<Item = u64> + '_ {
+        self.cpks.iter()
+            .filter(|cpk| cpk.on_disk && !cpk.cached)
+            .map(|cpk| cpk.size)
+    }
+
+    /// The plan as a JSON event, with the `disk_usage` computed for `disk_budget`
+    pub fn event<'a>(&'a self, disk_usage: &'a [DiskUsage], disk_budget: u64) -> Event<'a> {
+        Event::Plan {
+            cpks: &self.cpks,
+            file_count: self.file_count(),
+            output_size: self.output_size(),
+            output_needed: self.output_needed() + self.shadow_needed(),
+            temp_peak: self.temp_peak(disk_budget),
+            disks: disk_usage,
+        }
+    }
+
+    /// Prints every CPK of the plan and the totals
+    pub fn print(&self, args: &DumpArgs, disk_budget: u64) {
+        println!("--- Dump plan ---");
+        for cpk in &self.cpks {
+            let decryption = match (cpk.on_disk, cpk.cached) {
+                (false, _) => "decrypted in RAM",
+                (true, false) => "decrypted to the temp folder",
+                (true, true) => "already decrypted in the temp folder",
+            };
+            println!(
+                "{}\t{}\t{} files\t{} extracted\t{decryption}",
+                cpk.name,
+                format_size(cpk.size),
+                cpk.file_count,
+                format_size(cpk.output_size),
+            );
+        }
+
+        println!(
+            "\nTotal: {} CPK files, {} files, {} extracted ({} more on disk)",
+            self.cpks.len(),
+            self.file_count(),
+            format_size(self.output_size()),
+            format_size(self.output_needed() + self.shadow_needed()),
+        );
+        println!("Temp folder peak: {}", format_size(self.temp_peak(disk_budget)));
+
+        for usage in self.disk_usage(args, disk_budget) {
+            println!("{}", format_usage(&usage));
+        }
+        println!();
+    }
+}
+
+/// Describes the space needed and available on the disk
+pub fn format_usage(usage: &DiskUsage) -> String {
+    format!(
+        "Disk {} ({}): {} needed, {} free{}",
+        usage.disk.mount_point.display(),
+        usage.folders.join(", "),
+        format_size(usage.needed),
+        format_size(usage.disk.available),
+        if usage.fits() { "" } else { " - not enough space" },
+    )
+}
+
+/// Space a file of `size` bytes at `path` in the game takes in an archive of the format
+fn archived_size(format: OutputFormat, path: &str, size: u64) -> u64 {
+    match format {
+        OutputFormat::Folder => size,
+        OutputFormat::Tar => TarSink::entry_size(path, size),
+        OutputFormat::Zip => ZipSink::entry_size(path, size),
+        OutputFormat::Blob => BlobSink::entry_size(path, size),
+    }
+}
+
+fn archive_end_size(format: OutputFormat) -> u64 {
+    match format {
+        OutputFormat::Folder => 0,
+        OutputFormat::Tar => TarSink::end_size(),
+        OutputFormat::Zip => ZipSink::end_size(),
+        OutputFormat::Blob => BlobSink::end_size(),
+    }
+}
+
+/// Space taken by writing `size` bytes at `path`, over the file that may already be there
+fn needed_size(path: &Path, size: u64) -> u64 {
+    let existing = fs::metadata(path).map(|metadata| metadata.len()).unwrap_or_default();
+    size.saturating_sub(existing)
+}
+
+pub fn format_size(size: u64) -> String {
+    if size >= GB as u64 {
+        format!("{:.2} GiB", size as f64 / GB as f64)
+    } else {
+        format!("{:.2} MiB", size as f64 / MB as f64)
+    }
+}
@@ -0,0 +1,35 @@
+use std::io;
+
+/// The highest nice value, for the lowest CPU priority
+#[cfg(target_os = "linux")]
+const LOWEST_NICE: libc::c_int = 19;
+
+/// Constants of `ioprio_set`, which libc doesn't define. The idle class
+/// only gets the disk when no other program is using it.
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Gives the program the lowest CPU and disk priority. On Linux both belong to the calling
+/// thread and are inherited by the threads it starts, so this is called before starting any.
+#[cfg(target_os = "linux")]
+pub fn lower_priority() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOWEST_NICE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let io_priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io_priority) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lower_priority() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on Linux"))
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+use ievr_toolbox_core::{DumpEvent, ProgressSink, QueueMetrics};
+
+use crate::report::{Event, Report};
+
+/// The progress bars of a dump, one for the decryption of the CPKs and one for the extraction of their files
+pub struct DumpProgressBars {
+    mp: MultiProgress,
+    decryption_pb: ProgressBar,
+    extract_pb: ProgressBar,
+    metrics: QueueMetrics,
+}
+
+impl DumpProgressBars {
+    /// Starts the bars, `total_cpk_size` being the size of all the CPKs to decrypt.
+    /// The extraction bar shows the files waiting and the decrypted CPKs open from `metrics`.
+    pub fn new(total_cpk_size: u64, metrics: &QueueMetrics) -> Self {
+        let mp = MultiProgress::new();
+
+        let decryption_pb = mp.add(ProgressBar::new(total_cpk_size));
+        decryption_pb.set_style(ProgressStyle::with_template(
+            "{spinner:.green} Decrypting CPKs [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+        )
+        .unwrap()
+        .progress_chars("#>-"));
+        decryption_pb.enable_steady_tick(Duration::from_millis(100));
+
+        let extract_pb = mp.add(ProgressBar::new(0));
+        extract_pb.set_style(ProgressStyle::with_template(
+            "{spinner:.green} Extracting files [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}",
+        )
+        .unwrap()
+        .progress_chars("#>-"));
+        extract_pb.enable_steady_tick(Duration::from_millis(100));
+
+        Self { mp, decryption_pb, extract_pb, metrics: metrics.clone() }
+    }
+
+    fn show_queues(&self) {
+        let depths = self.metrics.depths();
+        self.extract_pb.set_message(format!("- {} files waiting, {} CPKs open", depths.files_waiting, depths.cpks_open));
+    }
+
+    pub fn finish(&self) {
+        self.decryption_pb.finish();
+        self.extract_pb.finish();
+    }
+
+    /// Removes the bars, to report an error
+    pub fn clear(&self) {
+        self.decryption_pb.finish_and_clear();
+        self.extract_pb.finish_and_clear();
+    }
+}
+
+impl ProgressSink for DumpProgressBars {
+    fn event(&self, event: DumpEvent) {
+        match event {
+            DumpEvent::CpkDecrypted { size, .. } => {
+                self.decryption_pb.inc(size);
+                self.show_queues();
+            }
+            DumpEvent::DecryptionDone => self.decryption_pb.finish(),
+            DumpEvent::FileQueued(file) => self.extract_pb.inc_length(file.extract_size as u64),
+            DumpEvent::FileExtracted(file) => {
+                self.extract_pb.inc(file.extract_size as u64);
+                self.show_queues();
+            }
+            // Printed above the bars so that they are not drawn over
+            DumpEvent::Warning(message) => self.mp.suspend(|| eprintln!("{message}")),
+            // The first error is returned by the pipeline and reported once the bars are removed
+            DumpEvent::Error(_) | DumpEvent::CryptProgress { .. } => {}
+        }
+    }
+}
+
+/// Sends the progress of a dump, a decryption or an encryption as JSON events, for `--json`
+pub struct JsonProgress {
+    report: Report,
+}
+
+impl JsonProgress {
+    pub fn new(report: Report) -> Self {
+        Self { report }
+    }
+}
+
+impl ProgressSink for JsonProgress {
+    fn event(&self, event: DumpEvent) {
+        match event {
+            DumpEvent::CpkDecrypted { path, size } => self.report.event(&Event::CpkDecrypted { path, size }),
+            DumpEvent::DecryptionDone => self.report.event(&Event::DecryptionDone),
+            DumpEvent::FileExtracted(file) => self.report.event(&Event::FileExtracted {
+                cpk: file.cpk_name.as_deref().unwrap_or_default(),
+                directory: file.directory.as_deref().unwrap_or_default(),
+                file_name: &file.file_name,
+                extract_size: file.extract_size,
+            }),
+            DumpEvent::Warning(message) => self.report.warning(message),
+            DumpEvent::CryptProgress { done, total } => self.report.event(&Event::Progress { done, total }),
+            // The total is already in the plan, and the first error is reported once the dump stops
+            DumpEvent::FileQueued(_) | DumpEvent::Error(_) => {}
+        }
+    }
+}
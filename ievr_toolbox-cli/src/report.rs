@@ -0,0 +1,163 @@
+use std::{fmt::Display, io, path::Path, process::exit};
+
+use ievr_toolbox_core::{ChangeKind, CpkInfo, PerformanceReport};
+use serde::Serialize;
+
+use crate::{CANCELLED_EXIT_CODE, preflight::{DiskUsage, PlannedCpk}};
+
+/// Why a command failed, which decides its exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    /// Any other error, such as a read or write that failed
+    Io,
+    /// Options that can't be used together
+    InvalidArguments,
+    /// The game folder or an input file doesn't exist
+    NotFound,
+    /// A file isn't what it should be, such as a corrupted CPK
+    InvalidData,
+    /// Not enough disk space for the output or the temp folder
+    InsufficientSpace,
+    /// A file or folder can't be read or written with the current permissions
+    PermissionDenied,
+    /// Stopped by Ctrl-C
+    Cancelled,
+}
+
+impl Failure {
+    pub fn of(e: &io::Error) -> Failure {
+        match e.kind() {
+            io::ErrorKind::InvalidInput => Failure::InvalidArguments,
+            io::ErrorKind::NotFound => Failure::NotFound,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Failure::InvalidData,
+            io::ErrorKind::StorageFull => Failure::InsufficientSpace,
+            io::ErrorKind::PermissionDenied => Failure::PermissionDenied,
+            io::ErrorKind::Interrupted => Failure::Cancelled,
+            _ => Failure::Io,
+        }
+    }
+
+    /// 2 is shared with the invalid arguments reported by clap
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Io => 1,
+            Failure::InvalidArguments => 2,
+            Failure::NotFound => 3,
+            Failure::InvalidData => 4,
+            Failure::InsufficientSpace => 5,
+            Failure::PermissionDenied => 6,
+            Failure::Cancelled => CANCELLED_EXIT_CODE,
+        }
+    }
+}
+
+/// What the commands report with `--json`, one object per line on the standard output,
+/// with its type in the `event` field
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// What the dump is going to extract and the space it needs, before it starts
+    Plan {
+        cpks: &'a [PlannedCpk],
+        file_count: usize,
+        output_size: u64,
+        output_needed: u64,
+        temp_peak: u64,
+        disks: &'a [DiskUsage],
+    },
+    CpkDecrypted { path: &'a Path, size: u64 },
+    DecryptionDone,
+    FileExtracted { cpk: &'a str, directory: &'a str, file_name: &'a str, extract_size: u32 },
+    Warning { message: &'a str },
+    /// The command failed, and exits with `exit_code`
+    Error { failure: Failure, exit_code: i32, message: &'a str },
+    DumpFinished {
+        dry_run: bool,
+        file_count: usize,
+        output_size: u64,
+        duration_secs: f64,
+        /// Size of the decrypted CPKs kept in the temp folder for the next dumps
+        cached_size: u64,
+        peak_files_waiting: usize,
+        peak_cpks_open: usize,
+    },
+    /// Where the time of the dump went, sent after `dump_finished` with `--stats`
+    Performance { report: &'a PerformanceReport },
+    /// Bytes of the file decrypted or encrypted so far
+    Progress { done: u64, total: u64 },
+    Decrypted { input: &'a Path, output: &'a Path },
+    Encrypted { input: &'a Path, output: &'a Path },
+    CpkInfo { path: &'a Path, info: &'a CpkInfo },
+    /// A file found in `cpk_list.cfg.bin`, with the folder and size of its CPK unless it is missing from the game
+    Located { file_name: &'a str, cpk: &'a str, folder: Option<&'a Path>, cpk_size: Option<u64> },
+    /// The file was extracted by `extract` from the CPK at `cpk`
+    Extracted { path: &'a str, cpk: &'a Path, output: &'a Path },
+    /// A file that differs between the versions compared by `diff`, with the sizes and CRC32 known for each version
+    FileChanged {
+        kind: ChangeKind,
+        path: &'a str,
+        old_size: Option<u32>,
+        new_size: Option<u32>,
+        old_crc32: Option<u32>,
+        new_crc32: Option<u32>,
+    },
+    DiffSummary { added: usize, removed: usize, modified: usize },
+    /// Files added to, removed from or moved between CPKs in `cpk_list.cfg.bin`
+    CpkListChanged { added: usize, removed: usize, moved: usize },
+    FileMoved { file_name: &'a str, old_cpks: Vec<&'a str>, new_cpks: Vec<&'a str> },
+    /// Both versions of the changed files were extracted to `output_folder`
+    ChangesExtracted { output_folder: &'a Path },
+}
+
+/// How the commands report what they do: text for people, or JSON lines with `--json`
+#[derive(Debug, Clone, Copy)]
+pub struct Report {
+    json: bool,
+}
+
+impl Report {
+    pub fn new(json: bool) -> Report {
+        Report { json }
+    }
+
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    /// Prints a line of text, which is left out in JSON mode
+    pub fn text(&self, message: impl Display) {
+        if !self.json {
+            println!("{message}");
+        }
+    }
+
+    /// Reports something wrong that doesn't stop the command
+    pub fn warning(&self, message: &str) {
+        if self.json {
+            self.event(&Event::Warning { message });
+        } else {
+            eprintln!("{message}");
+        }
+    }
+
+    /// Prints the event in JSON mode. A line is written at once, so events sent from several threads don't mix.
+    pub fn event(&self, event: &Event) {
+        if self.json {
+            println!("{}", serde_json::to_string(event).unwrap());
+        }
+    }
+
+    /// Reports the failure and exits with its exit code
+    pub fn fail(&self, failure: Failure, message: impl Display) -> ! {
+        let message = message.to_string();
+        if self.json {
+            self.event(&Event::Error { failure, exit_code: failure.exit_code(), message: &message });
+        } else if failure == Failure::Cancelled {
+            eprintln!("{message}");
+        } else {
+            eprintln!("Error: {message}");
+        }
+        exit(failure.exit_code())
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+use regex::Regex;
+
+use ievr_toolbox_core::{CpkIndexEntry, find_cpk_files, normalize_path, relative_cpk_path};
+
+use crate::{
+    MB, WhichArgs,
+    cpk_index::load_cpk_index,
+    game_folder::game_data_folder,
+    report::{Event, Report},
+};
+
+pub fn which(args: WhichArgs, report: Report) -> io::Result<()> {
+    let game_folder = game_data_folder(&args.input_folder)?;
+
+    let Some(cpk_index) = load_cpk_index(&game_folder)? else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no cpk_list.cfg.bin.", game_folder.display()),
+        ));
+    };
+
+    let entries: Vec<&CpkIndexEntry> = if args.regex {
+        let re = Regex::new(&args.file).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid regex {}: {e}", args.file))
+        })?;
+        cpk_index.filter(|file_name| re.is_match(file_name)).collect()
+    } else {
+        cpk_index.find(&args.file).collect()
+    };
+
+    if entries.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No file matching {} in cpk_list.cfg.bin.", args.file),
+        ));
+    }
+
+    // CPKs in different folders may have the same name, and the list may only give this name
+    let cpk_paths: BTreeMap<String, PathBuf> = find_cpk_files(&game_folder)?
+        .into_iter()
+        .map(|path| (relative_cpk_path(&game_folder, &path), path))
+        .collect();
+
+    for entry in entries {
+        let listed_cpk = normalize_path(&entry.cpk_name);
+        let mut found: Vec<&PathBuf> = cpk_paths.get(&listed_cpk).into_iter().collect();
+        if found.is_empty() {
+            found = cpk_paths.iter()
+                .filter(|(relative_path, _)| relative_path.rsplit_once('/').is_some_and(|(_, name)| name == listed_cpk))
+                .map(|(_, path)| path)
+                .collect();
+        }
+
+        if found.is_empty() {
+            report.text(format!("{}\t{}\t(missing from the game folder)", entry.file_name, entry.cpk_name));
+            report.event(&Event::Located { file_name: &entry.file_name, cpk: &entry.cpk_name, folder: None, cpk_size: None });
+        }
+
+        for cpk_path in found {
+            let cpk_size = fs::metadata(cpk_path)?.len();
+            let cpk_folder = cpk_path.parent()
+                .and_then(|folder| folder.strip_prefix(&game_folder).ok())
+                .filter(|folder| !folder.as_os_str().is_empty())
+                .map_or_else(|| PathBuf::from("data"), |folder| PathBuf::from("data").join(folder));
+
+            report.text(format!(
+                "{}\t{}\t{}\t{:.2} MiB",
+                entry.file_name,
+                entry.cpk_name,
+                cpk_folder.display(),
+                cpk_size as f64 / MB as f64
+            ));
+            report.event(&Event::Located {
+                file_name: &entry.file_name,
+                cpk: &entry.cpk_name,
+                folder: Some(&cpk_folder),
+                cpk_size: Some(cpk_size),
+            });
+        }
+    }
+
+    Ok(())
+}
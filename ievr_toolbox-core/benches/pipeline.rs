@@ -0,0 +1,320 @@
+//! Dumps synthetic encrypted CPKs with different thread counts and memory budgets.
+//!
+//! Run with `cargo bench -p ievr_toolbox-core`. The archives are generated once in the system
+//! temp folder: one set of CPKs with many small files, decrypted to the temp folder, and one
+//! with a few big files that compress well, decrypted in RAM.
+
+use std::{fs, path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use ievr_toolbox_core::{CancellationToken, DumpPipeline, FolderSink, SilentProgress};
+
+const MB: usize = 1024 * 1024;
+
+/// Copies read this many bytes after the one being written, the longest offset CRILAYLA allows
+const COPY_OFFSET: usize = 8194;
+
+fn pipeline(c: &mut Criterion) {
+    let root = std::env::temp_dir().join("ievr_toolbox_bench");
+    let many_small = synthetic_game(&root.join("many_small"), 6, 300, 8 * 1024..96 * 1024);
+    let few_big = synthetic_game(&root.join("few_big"), 3, 6, 3 * MB..6 * MB);
+    fs::create_dir_all(root.join("temp")).unwrap();
+
+    let max_threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let mut thread_counts = vec![1, 2, 4, max_threads];
+    thread_counts.sort_unstable();
+    thread_counts.dedup();
+
+    let mut group = c.benchmark_group("dump");
+    group.sample_size(10).measurement_time(Duration::from_secs(20));
+
+    for (name, cpks) in [("many_small", &many_small), ("few_big", &few_big)] {
+        for (budget, memory_limit) in [("unlimited", usize::MAX), ("64MiB", 64 * MB)] {
+            for &threads in &thread_counts {
+                let id = BenchmarkId::new(format!("{name}/{budget}"), format!("{threads} threads"));
+                group.bench_with_input(id, &threads, |b, &threads| {
+                    let output = root.join("output");
+                    b.iter_batched(
+                        || {
+                            let _ = fs::remove_dir_all(&output);
+                        },
+                        |()| {
+                            DumpPipeline::new(cpks.clone(), Arc::new(FolderSink::new(&output)))
+                                .threads(threads)
+                                .memory_limit(memory_limit)
+                                // The CPKs with many small files weigh about 3 MiB, the others less than 1 MiB
+                                .size_threshold(2 * MB)
+                                .temp_folder(root.join("temp"))
+                                .run()
+                                .unwrap();
+                        },
+                        BatchSize::PerIteration,
+                    )
+                });
+            }
+        }
+    }
+
+    group.finish();
+}
+
+/// Writes `cpk_count` encrypted CPKs of `file_count` compressed files each, unless they already exist
+fn synthetic_game(folder: &Path, cpk_count: usize, file_count: usize, file_sizes: std::ops::Range<usize>) -> Vec<PathBuf> {
+    fs::create_dir_all(folder).unwrap();
+    let mut rng = XorShift(0x9E37_79B9_7F4A_7C15 ^ (cpk_count * file_count) as u64);
+
+    (0..cpk_count)
+        .map(|index| {
+            let path = folder.join(format!("data_{index}.cpk"));
+            if path.exists() {
+                return path;
+            }
+
+            let files: Vec<(String, Vec<u8>, usize)> = (0..file_count)
+                .map(|file| {
+                    let size = file_sizes.start + rng.next() as usize % (file_sizes.end - file_sizes.start);
+                    let raw = compressible_data(&mut rng, size);
+                    (format!("file_{file}.bin"), crilayla(&raw), size)
+                })
+                .collect();
+
+            // The key depends on the name of the file, so the plain CPK has the same name
+            let plain = std::env::temp_dir().join(format!("ievr_toolbox_bench_{index}")).join(path.file_name().unwrap());
+            fs::create_dir_all(plain.parent().unwrap()).unwrap();
+            fs::write(&plain, cpk_data(&format!("data_{index}"), &files)).unwrap();
+            ievr_toolbox_core::encrypt(&plain, &path, &CancellationToken::new(), &SilentProgress).unwrap();
+            fs::remove_dir_all(plain.parent().unwrap()).unwrap();
+            path
+        })
+        .collect()
+}
+
+/// Random bytes repeated every [`COPY_OFFSET`] bytes, with some of them changed
+fn compressible_data(rng: &mut XorShift, size: usize) -> Vec<u8> {
+    let pattern: Vec<u8> = (0..COPY_OFFSET).map(|_| rng.next() as u8).collect();
+    let mut data: Vec<u8> = (0..size).map(|i| pattern[i % COPY_OFFSET]).collect();
+    for _ in 0..size / 2000 {
+        let index = rng.next() as usize % size;
+        data[index] = rng.next() as u8;
+    }
+    data
+}
+
+/// Compresses `raw` with CRILAYLA, using copies of the bytes [`COPY_OFFSET`] bytes later
+fn crilayla(raw: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+
+    // The data is compressed from its end, and its first 0x100 bytes are stored as is
+    let mut index = raw.len() - 1;
+    while index >= 0x100 {
+        let mut length = 0;
+        while index + COPY_OFFSET < raw.len()
+            && length < 5000
+            && index - length >= 0x100
+            && raw[index - length] == raw[index - length + COPY_OFFSET]
+        {
+            length += 1;
+        }
+
+        if length >= 3 {
+            bits.write(1, 1);
+            bits.write(COPY_OFFSET - 3, 13);
+
+            // The length is stored in levels of 2, 3 and 5 bits, then bytes until one isn't 255
+            let mut rest = length - 3;
+            let mut stored = false;
+            for (width, max) in [(2, 3), (3, 7), (5, 31)] {
+                let value = rest.min(max);
+                bits.write(value, width);
+                rest -= value;
+                if value != max {
+                    stored = true;
+                    break;
+                }
+            }
+            while !stored {
+                let value = rest.min(255);
+                bits.write(value, 8);
+                rest -= value;
+                stored = value != 255;
+            }
+
+            index -= length;
+        } else {
+            bits.write(0, 1);
+            bits.write(raw[index] as usize, 8);
+            index -= 1;
+        }
+    }
+
+    let mut stream = bits.finish();
+    stream.reverse();
+
+    let mut compressed = b"CRILAYLA".to_vec();
+    compressed.extend(((raw.len() - 0x100) as u32).to_le_bytes());
+    compressed.extend((stream.len() as u32).to_le_bytes());
+    compressed.extend(stream);
+    compressed.extend(&raw[..0x100]);
+    compressed
+}
+
+/// A CPK of the files in `dir_name`, given as their name, stored data and extracted size
+fn cpk_data(dir_name: &str, files: &[(String, Vec<u8>, usize)]) -> Vec<u8> {
+    const TOC_OFFSET: usize = 0x800;
+    const ALIGN: usize = 0x800;
+
+    let toc = |offsets: &[usize]| {
+        let rows = files.iter().zip(offsets).enumerate().map(|(id, ((name, data, extract_size), &offset))| {
+            vec![
+                Value::Str(dir_name.into()),
+                Value::Str(name.clone()),
+                Value::U32(data.len() as u32),
+                Value::U32(*extract_size as u32),
+                Value::U64(offset as u64),
+                Value::Str("<NULL>".into()),
+                Value::U32(id as u32),
+            ]
+        });
+        utf_table(b"TOC ", "CpkTocInfo", &["DirName", "FileName", "FileSize", "ExtractSize", "FileOffset", "UserString", "ID"], rows.collect())
+    };
+
+    // The offsets don't change the size of the TOC
+    let toc_size = toc(&vec![0; files.len()]).len();
+    let mut offsets = Vec::new();
+    let mut end = TOC_OFFSET + toc_size;
+    for (_, data, _) in files {
+        end = end.next_multiple_of(ALIGN);
+        offsets.push(end - TOC_OFFSET);
+        end += data.len();
+    }
+
+    let header = utf_table(
+        b"CPK ",
+        "CpkHeader",
+        &["ContentOffset", "ContentSize", "TocOffset", "TocSize", "Files", "Version", "Revision", "Align", "Codec"],
+        vec![vec![
+            Value::U64(TOC_OFFSET as u64),
+            Value::U64((end - TOC_OFFSET) as u64),
+            Value::U64(TOC_OFFSET as u64),
+            Value::U64(toc_size as u64),
+            Value::U32(files.len() as u32),
+            Value::U32(7),
+            Value::U32(14),
+            Value::U32(ALIGN as u32),
+            Value::U32(0),
+        ]],
+    );
+
+    let mut cpk = vec![0; end];
+    cpk[..header.len()].copy_from_slice(&header);
+    cpk[TOC_OFFSET..TOC_OFFSET + toc_size].copy_from_slice(&toc(&offsets));
+    for ((_, data, _), offset) in files.iter().zip(offsets) {
+        cpk[TOC_OFFSET + offset..TOC_OFFSET + offset + data.len()].copy_from_slice(data);
+    }
+    cpk
+}
+
+enum Value {
+    U32(u32),
+    U64(u64),
+    Str(String),
+}
+
+/// A UTF table whose columns all have a value in every row, with the types of the first row
+fn utf_table(magic: &[u8; 4], name: &str, columns: &[&str], rows: Vec<Vec<Value>>) -> Vec<u8> {
+    let mut strings = b"<NULL>\0".to_vec();
+    let mut string = |value: &str| {
+        let offset = strings.len() as u32;
+        strings.extend(value.as_bytes());
+        strings.push(0);
+        offset
+    };
+
+    let table_name = string(name);
+    let mut column_data = Vec::new();
+    for (column, value) in columns.iter().zip(&rows[0]) {
+        // Values stored in each row, with their type in the low bits
+        let kind = match value {
+            Value::U32(_) => 4,
+            Value::U64(_) => 6,
+            Value::Str(_) => 10,
+        };
+        column_data.push(0x50 | kind);
+        column_data.extend(string(column).to_be_bytes());
+    }
+
+    let mut row_data = Vec::new();
+    for row in &rows {
+        for value in row {
+            match value {
+                Value::U32(value) => row_data.extend(value.to_be_bytes()),
+                Value::U64(value) => row_data.extend(value.to_be_bytes()),
+                Value::Str(value) => row_data.extend(string(value).to_be_bytes()),
+            }
+        }
+    }
+    let row_size = row_data.len() / rows.len();
+
+    let rows_offset = 0x20 + column_data.len();
+    let strings_offset = rows_offset + row_data.len();
+    let table_size = strings_offset + strings.len();
+
+    let mut table = b"@UTF".to_vec();
+    table.extend(((table_size - 8) as u32).to_be_bytes());
+    table.extend(1u16.to_be_bytes());
+    table.extend(((rows_offset - 8) as u16).to_be_bytes());
+    table.extend(((strings_offset - 8) as u32).to_be_bytes());
+    table.extend(((table_size - 8) as u32).to_be_bytes());
+    table.extend(table_name.to_be_bytes());
+    table.extend((columns.len() as u16).to_be_bytes());
+    table.extend((row_size as u16).to_be_bytes());
+    table.extend((rows.len() as u32).to_be_bytes());
+    table.extend(column_data);
+    table.extend(row_data);
+    table.extend(strings);
+
+    let mut chunk = magic.to_vec();
+    chunk.extend(0xFFu32.to_le_bytes());
+    chunk.extend((table.len() as u32).to_le_bytes());
+    chunk.extend(0u32.to_le_bytes());
+    chunk.extend(table);
+    chunk
+}
+
+/// Packs bits from the most significant one of each byte
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_count: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: usize, width: u32) {
+        for bit in (0..width).rev() {
+            if self.bit_count.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let byte = self.bytes.last_mut().unwrap();
+            *byte |= (((value >> bit) & 1) as u8) << (7 - self.bit_count % 8);
+            self.bit_count += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+criterion_group!(benches, pipeline);
+criterion_main!(benches);
@@ -0,0 +1,506 @@
+use std::{
+    fs::{self, File, OpenOptions}, io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf},
+    sync::{Mutex, atomic::{AtomicUsize, Ordering}}, time::{SystemTime, UNIX_EPOCH}
+};
+
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{CpkFile, Decompressor, OutputSink, crc32, is_compressed};
+
+/// Ends a blob, after the offset of its index
+const BLOB_MAGIC: &[u8; 8] = b"IEVRBLOB";
+
+/// Size of a tar header, to which the content of the entries is padded
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// Files from this size need the zip64 fields, which have to be asked for before they are written
+const ZIP64_SIZE: u64 = u32::MAX as u64;
+
+/// Longest name a tar header holds, longer ones take an entry of their own
+const TAR_NAME_SIZE: usize = 100;
+
+/// Writes the files in a tar archive. Each worker decompresses its file on its own,
+/// and only the copy to the archive is done one file at a time.
+pub struct TarSink {
+    builder: Mutex<tar::Builder<BufWriter<File>>>,
+    extractor: Extractor,
+    mtime: u64,
+}
+
+impl TarSink {
+    /// Creates the archive at `path`, replacing any file already there
+    pub fn create(path: &Path) -> io::Result<TarSink> {
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Ok(TarSink {
+            builder: Mutex::new(tar::Builder::new(BufWriter::new(File::create(path)?))),
+            extractor: Extractor::default(),
+            mtime,
+        })
+    }
+
+    /// Folder where the files bigger than the memory budget are decompressed before being copied
+    /// to the archive. Defaults to the temp folder of the system.
+    pub fn spill_folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.extractor.spill_folder = folder.into();
+        self
+    }
+
+    /// Space a file of `size` bytes named `name` takes in the archive, with its header
+    pub fn entry_size(name: &str, size: u64) -> u64 {
+        let long_name = if name.len() > TAR_NAME_SIZE {
+            TAR_BLOCK_SIZE + (name.len() as u64 + 1).next_multiple_of(TAR_BLOCK_SIZE)
+        } else {
+            0
+        };
+        long_name + TAR_BLOCK_SIZE + size.next_multiple_of(TAR_BLOCK_SIZE)
+    }
+
+    /// Space taken by the end of the archive, after the entries
+    pub fn end_size() -> u64 {
+        2 * TAR_BLOCK_SIZE
+    }
+}
+
+impl OutputSink for TarSink {
+    fn write_file(&self, decompressor: &mut Decompressor, file: &CpkFile) -> io::Result<Option<u32>> {
+        let (mut extracted, crc32) = self.extractor.extract(decompressor, file)?;
+
+        // A stored file is copied as it is in the CPK, whose size may not be the extracted size of its TOC entry
+        let mut header = tar::Header::new_gnu();
+        header.set_size(extracted.len()?);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        header.set_entry_type(tar::EntryType::Regular);
+
+        self.builder.lock().unwrap().append_data(&mut header, entry_name(file), extracted.reader()?)?;
+        Ok(crc32)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut builder = self.builder.lock().unwrap();
+        builder.finish()?;
+        builder.get_mut().flush()
+    }
+}
+
+/// How the files are stored in a zip archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZipCompression {
+    /// As they are, which is the fastest
+    Store,
+    /// Compressed with deflate, which is smaller but much slower
+    Deflate,
+}
+
+/// Writes the files in a zip archive. Each worker decompresses its file and deflates it on its own,
+/// in a zip of one file that is copied to the archive as it is, so that only the copies are done
+/// one file at a time. A deflated file is held in memory along with its decompressed content,
+/// on top of the memory budget of the dump.
+pub struct ZipSink {
+    writer: Mutex<Option<ZipWriter<BufWriter<File>>>>,
+    compression: ZipCompression,
+    extractor: Extractor,
+}
+
+impl ZipSink {
+    /// Creates the archive at `path`, replacing any file already there
+    pub fn create(path: &Path, compression: ZipCompression) -> io::Result<ZipSink> {
+        Ok(ZipSink {
+            writer: Mutex::new(Some(ZipWriter::new(BufWriter::new(File::create(path)?)))),
+            compression,
+            extractor: Extractor::default(),
+        })
+    }
+
+    /// Folder where the files bigger than the memory budget are decompressed before being copied
+    /// to the archive. Defaults to the temp folder of the system.
+    pub fn spill_folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.extractor.spill_folder = folder.into();
+        self
+    }
+
+    /// Space a file of `size` bytes named `name` takes in the archive when stored, with its local header
+    /// and its central directory entry. A deflated file usually takes less.
+    pub fn entry_size(name: &str, size: u64) -> u64 {
+        // Both headers hold the name, and may need the zip64 fields
+        const LOCAL_HEADER_SIZE: u64 = 30 + 20;
+        const CENTRAL_HEADER_SIZE: u64 = 46 + 28;
+
+        size + LOCAL_HEADER_SIZE + CENTRAL_HEADER_SIZE + 2 * name.len() as u64
+    }
+
+    /// Space taken by the end of the archive, after the central directory
+    pub fn end_size() -> u64 {
+        // The end of central directory record, and its zip64 record and locator
+        22 + 56 + 20
+    }
+
+    /// Deflates the file in a zip of its own, which is the spill file for the files
+    /// too big to be decompressed in memory
+    fn deflate<W: Read + Write + Seek>(output: W, name: String, extracted: &mut Extracted) -> io::Result<ZipArchive<W>> {
+        let options = zip_options(CompressionMethod::Deflated, extracted.len()?);
+
+        let mut writer = ZipWriter::new(output);
+        writer.start_file(name, options)?;
+        io::copy(&mut extracted.reader()?, &mut writer)?;
+        let mut output = writer.finish()?;
+
+        output.rewind()?;
+        Ok(ZipArchive::new(output)?)
+    }
+
+    /// Copies the first file of `deflated` to the archive
+    fn copy_deflated<R: Read + Seek>(&self, mut deflated: ZipArchive<R>) -> io::Result<()> {
+        let file = deflated.by_index_raw(0)?;
+
+        let mut writer = self.writer.lock().unwrap();
+        let writer = writer.as_mut().ok_or_else(finished)?;
+        writer.raw_copy_file(file)?;
+        Ok(())
+    }
+}
+
+impl OutputSink for ZipSink {
+    fn write_file(&self, decompressor: &mut Decompressor, file: &CpkFile) -> io::Result<Option<u32>> {
+        let (mut extracted, crc32) = self.extractor.extract(decompressor, file)?;
+        let name = entry_name(file);
+
+        match self.compression {
+            ZipCompression::Store => {
+                let options = zip_options(CompressionMethod::Stored, extracted.len()?);
+
+                let mut writer = self.writer.lock().unwrap();
+                let writer = writer.as_mut().ok_or_else(finished)?;
+                writer.start_file(name, options)?;
+                io::copy(&mut extracted.reader()?, writer)?;
+            }
+            ZipCompression::Deflate if matches!(extracted, Extracted::Spilled(_)) => {
+                let spill = self.extractor.spill_file()?;
+                self.copy_deflated(Self::deflate(&spill.file, name, &mut extracted)?)?;
+            }
+            ZipCompression::Deflate => {
+                self.copy_deflated(Self::deflate(Cursor::new(Vec::new()), name, &mut extracted)?)?;
+            }
+        }
+
+        Ok(crc32)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer.finish()?.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Options of a zip entry holding `size` bytes
+fn zip_options(method: CompressionMethod, size: u64) -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(method)
+        .large_file(size >= ZIP64_SIZE)
+}
+
+/// A file of a blob, and where it is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobEntry {
+    pub path: String,
+    pub offset: u64,
+    pub size: u64,
+    /// CRC32 of the file, if the dump computed checksums
+    pub crc32: Option<u32>,
+}
+
+/// Writes the files one after the other in a single file, followed by their index.
+/// The index is a JSON array of [`BlobEntry`], followed by its offset as a little-endian u64
+/// and `IEVRBLOB`, so that it can be found from the end of the file.
+pub struct BlobSink {
+    state: Mutex<BlobState>,
+    extractor: Extractor,
+}
+
+struct BlobState {
+    output: BufWriter<File>,
+    offset: u64,
+    entries: Vec<BlobEntry>,
+    finished: bool,
+}
+
+impl BlobSink {
+    /// Creates the blob at `path`, replacing any file already there
+    pub fn create(path: &Path) -> io::Result<BlobSink> {
+        Ok(BlobSink {
+            state: Mutex::new(BlobState {
+                output: BufWriter::new(File::create(path)?),
+                offset: 0,
+                entries: Vec::new(),
+                finished: false,
+            }),
+            extractor: Extractor::default(),
+        })
+    }
+
+    /// Folder where the files bigger than the memory budget are decompressed before being copied
+    /// to the blob. Defaults to the temp folder of the system.
+    pub fn spill_folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.extractor.spill_folder = folder.into();
+        self
+    }
+
+    /// Space a file of `size` bytes named `name` takes in the blob, with its entry in the index
+    pub fn entry_size(name: &str, size: u64) -> u64 {
+        // The JSON keys and the longest numbers
+        const INDEX_ENTRY_SIZE: u64 = 40 + 20 + 20 + 10;
+
+        size + INDEX_ENTRY_SIZE + name.len() as u64
+    }
+
+    /// Space taken by the end of the blob, after the index
+    pub fn end_size() -> u64 {
+        8 + BLOB_MAGIC.len() as u64
+    }
+
+    /// Reads the index of the blob at `path`
+    pub fn read_index(path: &Path) -> io::Result<Vec<BlobEntry>> {
+        let mut blob = File::open(path)?;
+
+        let mut footer = [0u8; 16];
+        blob.seek(SeekFrom::End(-16))?;
+        blob.read_exact(&mut footer)?;
+        if &footer[8..] != BLOB_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a blob", path.display())));
+        }
+
+        let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_size = blob.seek(SeekFrom::End(-16))? - index_offset;
+        blob.seek(SeekFrom::Start(index_offset))?;
+
+        Ok(serde_json::from_reader(blob.take(index_size))?)
+    }
+}
+
+impl OutputSink for BlobSink {
+    fn write_file(&self, decompressor: &mut Decompressor, file: &CpkFile) -> io::Result<Option<u32>> {
+        let (mut extracted, crc32) = self.extractor.extract(decompressor, file)?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return Err(finished());
+        }
+
+        let size = io::copy(&mut extracted.reader()?, &mut state.output)?;
+        let offset = state.offset;
+        state.offset += size;
+        state.entries.push(BlobEntry { path: entry_name(file), offset, size, crc32 });
+
+        Ok(crc32)
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return Ok(());
+        }
+        state.finished = true;
+
+        let index = serde_json::to_vec(&state.entries)?;
+        let index_offset = state.offset;
+        state.output.write_all(&index)?;
+        state.output.write_all(&index_offset.to_le_bytes())?;
+        state.output.write_all(BLOB_MAGIC)?;
+        state.output.flush()
+    }
+}
+
+/// Path of the file in an archive, with `/` separators
+fn entry_name(file: &CpkFile) -> String {
+    match file.directory.as_deref().map(|directory| directory.trim_matches(['/', '\\'])) {
+        Some(directory) if !directory.is_empty() => format!("{}/{}", directory.replace('\\', "/"), file.file_name),
+        _ => file.file_name.clone(),
+    }
+}
+
+fn finished() -> io::Error {
+    io::Error::other("The archive is already finished")
+}
+
+/// Decompresses the files of the archive sinks in memory, or to a spill file in low memory mode
+#[derive(Debug)]
+struct Extractor {
+    spill_folder: PathBuf,
+    spill_count: AtomicUsize,
+}
+
+impl Default for Extractor {
+    fn default() -> Self {
+        Extractor { spill_folder: std::env::temp_dir(), spill_count: AtomicUsize::new(0) }
+    }
+}
+
+impl Extractor {
+    /// Extracts the file, returning its CRC32 if the decompressor computes checksums
+    fn extract<'a>(&self, decompressor: &mut Decompressor, file: &'a CpkFile) -> io::Result<(Extracted<'a>, Option<u32>)> {
+        // The stored bytes are read from the CPK, then written extracted
+        decompressor.throttle().consume(file.file_size as usize + file.extract_size as usize);
+
+        if !is_compressed(file) {
+            let data = file.data().unwrap_or_default();
+            return Ok((Extracted::Stored(data), decompressor.checksum().then(|| crc32(data))));
+        }
+
+        // The file is decompressed from its end, so it can only be streamed once complete
+        if decompressor.low_memory() {
+            let spill = self.spill_file()?;
+            let crc32 = decompressor.decompress(&spill.path, file)?;
+            return Ok((Extracted::Spilled(spill), crc32));
+        }
+
+        let data = decompressor.decompress_to_vec(file)?;
+        let crc32 = decompressor.checksum().then(|| crc32(&data));
+        Ok((Extracted::Memory(data), crc32))
+    }
+
+    /// Creates an empty spill file, removed once dropped
+    fn spill_file(&self) -> io::Result<SpillFile> {
+        fs::create_dir_all(&self.spill_folder)?;
+
+        let index = self.spill_count.fetch_add(1, Ordering::Relaxed);
+        let path = self.spill_folder.join(format!("ievr_toolbox_{}_{index}.spill", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(SpillFile { path, file })
+    }
+}
+
+/// The content of an extracted file, to copy to an archive
+enum Extracted<'a> {
+    /// Stored without compression in the CPK
+    Stored(&'a [u8]),
+    Memory(Vec<u8>),
+    /// Decompressed to a spill file, for the files bigger than the memory budget
+    Spilled(SpillFile),
+}
+
+impl Extracted<'_> {
+    /// Size of the content, which is what gets copied to the archive
+    fn len(&self) -> io::Result<u64> {
+        Ok(match self {
+            Extracted::Stored(data) => data.len() as u64,
+            Extracted::Memory(data) => data.len() as u64,
+            Extracted::Spilled(spill) => spill.file.metadata()?.len(),
+        })
+    }
+
+    fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        Ok(match self {
+            Extracted::Stored(data) => Box::new(*data),
+            Extracted::Memory(data) => Box::new(&data[..]),
+            Extracted::Spilled(spill) => {
+                spill.file.rewind()?;
+                Box::new(&spill.file)
+            }
+        })
+    }
+}
+
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::CpkData;
+
+    fn stored_file(directory: &str, file_name: &str, content: &[u8]) -> CpkFile {
+        CpkFile {
+            directory: Some(Arc::from(directory)),
+            file_name: file_name.to_string(),
+            file_size: content.len() as u32,
+            extract_size: content.len() as u32,
+            data: Some(Arc::new(CpkData::Small(content.to_vec()))),
+            ..Default::default()
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ievr_toolbox_{}_{name}", std::process::id()))
+    }
+
+    #[test]
+    fn blob_index_round_trip() {
+        let path = temp_path("round_trip.blob");
+        let files = [
+            stored_file("data/common", "chara_param.cfg.bin", b"chara"),
+            stored_file("", "root.bin", b""),
+            stored_file("\\data\\ui\\", "face.png", &[0x89, b'P', b'N', b'G', 0, 255]),
+        ];
+
+        let sink = BlobSink::create(&path).unwrap();
+        let mut decompressor = Decompressor::with_checksum();
+        for file in &files {
+            sink.write_file(&mut decompressor, file).unwrap();
+        }
+        sink.finish().unwrap();
+        // Finishing twice doesn't write the index again
+        sink.finish().unwrap();
+
+        let index = BlobSink::read_index(&path).unwrap();
+        let blob = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let paths: Vec<&str> = index.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["data/common/chara_param.cfg.bin", "root.bin", "data/ui/face.png"]);
+
+        for (entry, file) in index.iter().zip(&files) {
+            let content = &blob[entry.offset as usize..(entry.offset + entry.size) as usize];
+            assert_eq!(content, file.data().unwrap());
+            assert_eq!(entry.crc32, Some(crc32(content)));
+        }
+    }
+
+    #[test]
+    fn blob_without_checksums() {
+        let path = temp_path("no_checksum.blob");
+
+        let sink = BlobSink::create(&path).unwrap();
+        sink.write_file(&mut Decompressor::default(), &stored_file("data", "a.bin", b"abc")).unwrap();
+        sink.finish().unwrap();
+
+        let index = BlobSink::read_index(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(index.len(), 1);
+        assert_eq!((index[0].offset, index[0].size, index[0].crc32), (0, 3, None));
+    }
+
+    #[test]
+    fn not_a_blob() {
+        let path = temp_path("not_a.blob");
+        fs::write(&path, b"this file doesn't end with the blob magic").unwrap();
+
+        let error = BlobSink::read_index(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,31 @@
+use std::{io, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+
+/// Tells long operations to stop as soon as they can. Clones share the same flag,
+/// so the token can be cancelled from another thread, such as a Ctrl-C handler.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns an [`io::ErrorKind::Interrupted`] error once the token is cancelled
+    pub fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "The operation was cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -2,23 +2,64 @@ use std::{fs::OpenOptions, io, path::PathBuf};
 
 mod utils;
 mod reverse_bit_reader;
+mod streaming_output;
 
 use memmap2::MmapMut;
 pub use utils::is_compressed;
 
 use reverse_bit_reader::ReverseBitReader;
+use streaming_output::StreamingOutput;
 
-use crate::cpk_file::CpkFile;
+use crate::{IoThrottle, cpk_file::CpkFile, crc32::crc32};
 
 /// Constants defined in the original algorithm
 const UNCOMPRESSED_DATA_SIZE: usize = 0x100;
 const MIN_COPY_LENGTH: usize = 3;
+/// Copies read at most this many bytes after the one being written
+const MAX_COPY_OFFSET: usize = (1 << 13) - 1 + MIN_COPY_LENGTH;
 
 #[derive(Debug, Default)]
-pub struct Decompressor {}
+pub struct Decompressor {
+    checksum: bool,
+    low_memory: bool,
+    throttle: IoThrottle,
+}
 
 impl Decompressor {
-    pub fn decompress(&mut self, extracted_file_path: &PathBuf, extracted_file: &CpkFile) -> std::io::Result<()> {
+    /// Memory used to decompress a file in low memory mode, whatever its size
+    pub const LOW_MEMORY_SIZE: usize = 4 * 1024 * 1024;
+
+    /// A decompressor that also computes the CRC32 of every file it writes
+    pub fn with_checksum() -> Self {
+        Self { checksum: true, ..Self::default() }
+    }
+
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    /// In low memory mode, files are written through a buffer of [`LOW_MEMORY_SIZE`](Self::LOW_MEMORY_SIZE)
+    /// bytes instead of being decompressed in a mapping of the whole file, which is slower but lets files
+    /// bigger than the memory budget be extracted
+    pub fn set_low_memory(&mut self, low_memory: bool) {
+        self.low_memory = low_memory;
+    }
+
+    pub fn low_memory(&self) -> bool {
+        self.low_memory
+    }
+
+    /// Counts the bytes read from the CPKs and written by [`decompress_files`](crate::decompress_files) against `throttle`
+    pub fn set_throttle(&mut self, throttle: &IoThrottle) {
+        self.throttle = throttle.clone();
+    }
+
+    pub fn throttle(&self) -> &IoThrottle {
+        &self.throttle
+    }
+
+    /// Returns the CRC32 of the decompressed file if checksums are enabled
+    pub fn decompress(&mut self, extracted_file_path: &PathBuf, extracted_file: &CpkFile) -> std::io::Result<Option<u32>> {
         let decompressed_file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -28,24 +69,66 @@ impl Decompressor {
 
         decompressed_file.set_len(extracted_file.extract_size as u64)?;
 
+        if self.low_memory {
+            let mut output = StreamingOutput::new(&decompressed_file, extracted_file.extract_size as usize, Self::LOW_MEMORY_SIZE);
+            if let Some(compressed_data) = extracted_file.data() {
+                decompress_layla(compressed_data, &mut output)?;
+            }
+            return output.finish(self.checksum);
+        }
+
         let mut mmap = unsafe {
             MmapMut::map_mut(&decompressed_file)?
         };
 
         if let Some(compressed_data) = extracted_file.data() {
-            decompress_layla(compressed_data, &mut mmap)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Decompression failed"))?;
+            decompress_layla(compressed_data, &mut mmap[..])?;
         }
 
-        Ok(())
+        Ok(self.checksum.then(|| crc32(&mmap)))
     }
 
+    /// Decompresses the file in memory instead of writing it to disk
+    pub fn decompress_to_vec(&mut self, extracted_file: &CpkFile) -> std::io::Result<Vec<u8>> {
+        let mut output = vec![0u8; extracted_file.extract_size as usize];
+
+        if let Some(compressed_data) = extracted_file.data() {
+            decompress_layla(compressed_data, &mut output[..])?;
+        }
+
+        Ok(output)
+    }
+
+}
+
+
+/// Where [`decompress_layla`] writes the decompressed file, from its end to its start
+trait LaylaOutput {
+    /// The byte already written at `index`, if it can still be read
+    fn read(&self, index: usize) -> Option<u8>;
+    fn write(&mut self, index: usize, byte: u8) -> io::Result<()>;
+}
+
+impl LaylaOutput for [u8] {
+    #[inline(always)]
+    fn read(&self, index: usize) -> Option<u8> {
+        self.get(index).copied()
+    }
+
+    #[inline(always)]
+    fn write(&mut self, index: usize, byte: u8) -> io::Result<()> {
+        *self.get_mut(index).ok_or_else(decompression_failed)? = byte;
+        Ok(())
+    }
 }
 
+fn decompression_failed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Decompression failed")
+}
 
-fn decompress_layla(compressed_data: &[u8], output: &mut MmapMut) -> Option<()> {
+fn decompress_layla<O: LaylaOutput + ?Sized>(compressed_data: &[u8], output: &mut O) -> io::Result<()> {
     if compressed_data.len() < 0x10 {
-        return None;
+        return Err(decompression_failed());
     }
     
     // uncompSizeOfCompData is at offset 8 (u32 LE)
@@ -66,13 +149,9 @@ fn decompress_layla(compressed_data: &[u8], output: &mut MmapMut) -> Option<()>
     let header_src_start = uncomp_header_offset + 0x10;
 
     if header_src_start + UNCOMPRESSED_DATA_SIZE > compressed_data.len() {
-        return None; // Out of bounds safety
+        return Err(decompression_failed()); // Out of bounds safety
     }
 
-    // Copy the header to the start of the output
-    output[0..UNCOMPRESSED_DATA_SIZE]
-        .copy_from_slice(&compressed_data[header_src_start..header_src_start + UNCOMPRESSED_DATA_SIZE]);
-
     let mut reader = ReverseBitReader::new(compressed_data, header_src_start);
 
     // We start reading from the back of the file
@@ -115,13 +194,10 @@ fn decompress_layla(compressed_data: &[u8], output: &mut MmapMut) -> Option<()>
             for _ in 0..length {
                 let src_idx = write_index + offset;
 
-                if src_idx >= output.len() {
-                   // In standard LZ77, this shouldn't happen with valid data.
-                   // However, return None or break to avoid panic.
-                   return None; 
-                }
-                
-                output[write_index] = output[src_idx];
+                // In standard LZ77, a byte out of the output shouldn't happen with valid data.
+                // However, return an error to avoid panic.
+                let byte = output.read(src_idx).ok_or_else(decompression_failed)?;
+                output.write(write_index, byte)?;
                 
                 if write_index == 0 { break; } // Safety for usize underflow
                 write_index -= 1;
@@ -132,11 +208,17 @@ fn decompress_layla(compressed_data: &[u8], output: &mut MmapMut) -> Option<()>
         } else {
             // Verbatim Byte
             let byte = reader.read_bits(8) as u8;
-            output[write_index] = byte;
+            output.write(write_index, byte)?;
             
             if write_index == 0 { break; }
             write_index -= 1;
         }
     }
-    Some(())
-}
\ No newline at end of file
+
+    // Copy the header to the start of the output, last since the output is written backwards
+    for (index, &byte) in compressed_data[header_src_start..header_src_start + UNCOMPRESSED_DATA_SIZE].iter().enumerate() {
+        output.write(index, byte)?;
+    }
+
+    Ok(())
+}
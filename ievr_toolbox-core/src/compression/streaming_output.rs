@@ -0,0 +1,191 @@
+use std::{fs::File, io::{self, Read, Seek, SeekFrom, Write}};
+
+use crate::crc32::crc32_update;
+
+use super::{LaylaOutput, MAX_COPY_OFFSET};
+
+/// Writes a decompressed file through a buffer of a fixed size instead of holding all of it.
+///
+/// CRILAYLA decompresses from the end of the file to its start, and only copies bytes from
+/// less than [`MAX_COPY_OFFSET`] after the one being written. The buffer covers the part of the
+/// file being decompressed, and once it is full, everything but these last bytes is written
+/// to the file and the buffer moves towards the start of the file.
+pub(super) struct StreamingOutput<'a> {
+    file: &'a File,
+    size: usize,
+    buffer: Vec<u8>,
+    /// Offset in the file of the first byte of the buffer
+    start: usize,
+    /// Offset in the file where the bytes already written begin
+    end: usize,
+}
+
+impl<'a> StreamingOutput<'a> {
+    pub fn new(file: &'a File, size: usize, buffer_size: usize) -> Self {
+        debug_assert!(buffer_size > MAX_COPY_OFFSET);
+
+        let buffer_size = buffer_size.min(size);
+        Self {
+            file,
+            size,
+            buffer: vec![0; buffer_size],
+            start: size - buffer_size,
+            end: size,
+        }
+    }
+
+    /// Writes the rest of the buffer, and returns the CRC32 of the whole file if `checksum` is set
+    pub fn finish(mut self, checksum: bool) -> io::Result<Option<u32>> {
+        self.write_buffer(self.start)?;
+
+        if !checksum {
+            return Ok(None);
+        }
+
+        // The file is written backwards, so it is read again to hash it in order
+        let mut crc = 0;
+        let mut remaining = self.size;
+        self.file.seek(SeekFrom::Start(0))?;
+        while remaining > 0 {
+            let length = remaining.min(self.buffer.len());
+            let read = self.file.read(&mut self.buffer[..length])?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            crc = crc32_update(crc, &self.buffer[..read]);
+            remaining -= read;
+        }
+
+        Ok(Some(crc))
+    }
+
+    /// Moves the buffer towards the start of the file until it holds `index`
+    fn move_to(&mut self, index: usize) -> io::Result<()> {
+        while index < self.start {
+            // The bytes that can still be copied stay at the start of the buffer
+            let kept = self.start + MAX_COPY_OFFSET;
+            self.write_buffer(kept)?;
+
+            let start = kept.saturating_sub(self.buffer.len());
+            self.buffer.copy_within(0..MAX_COPY_OFFSET, self.start - start);
+            self.start = start;
+            self.end = kept;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the bytes of the buffer from offset `from` of the file up to the bytes already written
+    fn write_buffer(&mut self, from: usize) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(from as u64))?;
+        self.file.write_all(&self.buffer[from - self.start..self.end - self.start])
+    }
+}
+
+impl LaylaOutput for StreamingOutput<'_> {
+    #[inline(always)]
+    fn read(&self, index: usize) -> Option<u8> {
+        if index < self.start || index >= self.end {
+            return None;
+        }
+        Some(self.buffer[index - self.start])
+    }
+
+    #[inline(always)]
+    fn write(&mut self, index: usize, byte: u8) -> io::Result<()> {
+        if index >= self.end {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Decompression failed"));
+        }
+        if index < self.start {
+            self.move_to(index)?;
+        }
+
+        self.buffer[index - self.start] = byte;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, OpenOptions}, path::PathBuf};
+
+    use super::*;
+    use crate::crc32;
+
+    fn byte_at(index: usize) -> u8 {
+        (index * 7 % 251) as u8
+    }
+
+    /// Writes `size` bytes from the end of the file to its start like the decompressor,
+    /// checking that the bytes it may copy can still be read, and returns what the file holds
+    fn write_backwards(name: &str, size: usize, buffer_size: usize) -> (Vec<u8>, Option<u32>) {
+        let path: PathBuf = std::env::temp_dir().join(format!("ievr_toolbox_{}_{name}.test", std::process::id()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let mut output = StreamingOutput::new(&file, size, buffer_size);
+        for index in (0..size).rev() {
+            for copied in [index + 1, index + MAX_COPY_OFFSET] {
+                if copied < size {
+                    assert_eq!(output.read(copied), Some(byte_at(copied)), "byte {copied} can't be copied to {index}");
+                }
+            }
+            output.write(index, byte_at(index)).unwrap();
+        }
+        let crc32 = output.finish(true).unwrap();
+
+        drop(file);
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        (written, crc32)
+    }
+
+    fn expected(size: usize) -> Vec<u8> {
+        (0..size).map(byte_at).collect()
+    }
+
+    #[test]
+    fn file_smaller_than_the_buffer() {
+        let (written, crc32) = write_backwards("smaller", 1000, 4 * MAX_COPY_OFFSET);
+        assert_eq!(written, expected(1000));
+        assert_eq!(crc32, Some(crc32::crc32(&written)));
+    }
+
+    #[test]
+    fn file_as_big_as_the_buffer() {
+        let size = 2 * MAX_COPY_OFFSET;
+        let (written, crc32) = write_backwards("same_size", size, size);
+        assert_eq!(written, expected(size));
+        assert_eq!(crc32, Some(crc32::crc32(&written)));
+    }
+
+    #[test]
+    fn buffer_moved_many_times() {
+        let size = 10 * MAX_COPY_OFFSET + 123;
+        let (written, crc32) = write_backwards("moved", size, MAX_COPY_OFFSET + 1);
+        assert_eq!(written, expected(size));
+        assert_eq!(crc32, Some(crc32::crc32(&written)));
+    }
+
+    #[test]
+    fn buffer_ending_one_byte_after_the_file_start() {
+        // After the first move the buffer starts at byte 1, so byte 0 takes a move of its own
+        let buffer_size = 2 * MAX_COPY_OFFSET;
+        let size = buffer_size + (buffer_size - MAX_COPY_OFFSET) + 1;
+        let (written, _) = write_backwards("one_byte", size, buffer_size);
+        assert_eq!(written, expected(size));
+    }
+
+    #[test]
+    fn writing_after_the_written_bytes_fails() {
+        let path = std::env::temp_dir().join(format!("ievr_toolbox_{}_overflow.test", std::process::id()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+
+        let mut output = StreamingOutput::new(&file, 100, 2 * MAX_COPY_OFFSET);
+        assert!(output.write(100, 0).is_err());
+        assert_eq!(output.read(100), None);
+
+        drop(output);
+        drop(file);
+        fs::remove_file(&path).unwrap();
+    }
+}
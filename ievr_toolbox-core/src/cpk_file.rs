@@ -1,9 +1,14 @@
-use std::{cmp::Ordering, sync::Arc};
+use std::{cmp::Ordering, path::Path, sync::Arc};
 
 use crate::{CpkData, DecryptedCpk};
 
 #[derive(Debug, Default)]
 pub struct CpkFile {
+    // Name of the CPK the file comes from, when known
+    pub cpk_name: Option<Arc<str>>,
+    // Path of the CPK the file comes from, when known
+    pub cpk_path: Option<Arc<Path>>,
+
     // CPK self Metadata
     pub user_string: Option<Arc<str>>,
     pub directory: Option<Arc<str>>,
@@ -12,7 +17,7 @@ pub struct CpkFile {
     pub file_size: u32,
     pub extract_size: u32,
 
-    data: Option<DecryptedCpk>,
+    pub(crate) data: Option<DecryptedCpk>,
 }
 
 impl CpkFile {
@@ -20,6 +25,21 @@ impl CpkFile {
         self.data = Some(decrypted_cpk.clone());
     }
 
+    /// A copy of this file backed by `data`, which only contains the file's bytes
+    pub(crate) fn standalone(&self, data: DecryptedCpk) -> CpkFile {
+        CpkFile {
+            cpk_name: self.cpk_name.clone(),
+            cpk_path: self.cpk_path.clone(),
+            user_string: self.user_string.clone(),
+            directory: self.directory.clone(),
+            file_name: self.file_name.clone(),
+            file_offset: 0,
+            file_size: self.file_size,
+            extract_size: self.extract_size,
+            data: Some(data),
+        }
+    }
+
     pub fn compression_header(&self) -> Option<&[u8]> {
         if let Some(ref data) = self.data {
             Some(&data[self.file_offset as usize..self.file_offset as usize + 8])
@@ -43,6 +63,13 @@ impl CpkFile {
         None
     }
 
+    /// Drops the file, and returns the data of its CPK if no other file holds it anymore.
+    /// Unlike [`last_cpk_file`](Self::last_cpk_file), only one file of the CPK can get it
+    /// when several of them are dropped at the same time.
+    pub fn into_last_data(self) -> Option<CpkData> {
+        self.data.and_then(Arc::into_inner)
+    }
+
     pub fn cpk_size(&self) -> Option<usize> {
         if let Some(data) = &self.data {
             return Some(data.len());
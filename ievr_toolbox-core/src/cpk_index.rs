@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap, fs::{self, File}, io::{self, BufReader, BufWriter}, path::Path, time::UNIX_EPOCH
+};
+
+use ievr_cfg_bin_editor_core::{Value, parse_database};
+use serde::{Deserialize, Serialize};
+
+use crate::{criware_crypt::CriwareCrypt, normalize_path};
+
+/// Bumped whenever the layout of the cache changes
+const CACHE_VERSION: u32 = 2;
+
+const CPK_TABLE: &str = "CPK_ITEM";
+
+/// A column of the `CPK_ITEM` table we rely on. The cfg.bin format doesn't name the
+/// values of a row, so each column is given a name along with where the current version
+/// of the game puts it and what its values look like.
+#[derive(Debug, Clone, Copy)]
+struct CpkListColumn {
+    name: &'static str,
+    expected: Option<usize>,
+    matches: fn(&str) -> bool,
+}
+
+const FILE_NAME_COLUMN: CpkListColumn = CpkListColumn { name: "file name", expected: Some(1), matches: is_file_name };
+const CPK_NAME_COLUMN: CpkListColumn = CpkListColumn { name: "CPK name", expected: Some(3), matches: is_cpk_name };
+const DIRECTORY_COLUMN: CpkListColumn = CpkListColumn { name: "directory", expected: None, matches: is_directory };
+
+impl CpkListColumn {
+    /// Position of the column in the rows: where it is expected if its values still match,
+    /// or else the first other column whose values all match
+    fn find(&self, rows: &[Vec<Option<&str>>], taken: &[usize]) -> Option<usize> {
+        if let Some(expected) = self.expected && is_column(rows, expected, self.matches) {
+            return Some(expected);
+        }
+
+        let column_count = rows.iter().map(Vec::len).min().unwrap_or_default();
+        (0..column_count).find(|column| !taken.contains(column) && is_column(rows, *column, self.matches))
+    }
+
+    /// Like [`find`](Self::find), for a column the index can't be built without
+    fn require(&self, rows: &[Vec<Option<&str>>], taken: &[usize]) -> io::Result<usize> {
+        self.find(rows, taken).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The CPK_ITEM table of the CPK list has no {} column", self.name),
+        ))
+    }
+}
+
+/// Position of the columns of the `CPK_ITEM` table we rely on
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct CpkListSchema {
+    file_name: usize,
+    cpk_name: usize,
+    directory: Option<usize>,
+}
+
+impl CpkListSchema {
+    /// Finds the columns by their name. A missing file name or CPK name column is an error,
+    /// so that the CPK list isn't used in place of the TOCs of the CPKs.
+    fn detect(rows: &[Vec<Option<&str>>]) -> io::Result<CpkListSchema> {
+        if rows.is_empty() {
+            return Ok(CpkListSchema { file_name: 0, cpk_name: 0, directory: None });
+        }
+
+        let cpk_name = CPK_NAME_COLUMN.require(rows, &[])?;
+        let file_name = FILE_NAME_COLUMN.require(rows, &[cpk_name])?;
+        let directory = DIRECTORY_COLUMN.find(rows, &[cpk_name, file_name]);
+
+        Ok(CpkListSchema { file_name, cpk_name, directory })
+    }
+}
+
+/// Whether every row holds a string satisfying `predicate` in this column
+fn is_column(rows: &[Vec<Option<&str>>], column: usize, predicate: fn(&str) -> bool) -> bool {
+    rows.iter().all(|row| row.get(column).copied().flatten().is_some_and(predicate))
+}
+
+fn is_cpk_name(value: &str) -> bool {
+    value.to_lowercase().ends_with(".cpk")
+}
+
+fn is_directory(value: &str) -> bool {
+    value.contains(['/', '\\']) && !is_cpk_name(value)
+}
+
+fn is_file_name(value: &str) -> bool {
+    !value.is_empty() && !value.contains(['/', '\\']) && !is_cpk_name(value)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CpkIndexEntry {
+    /// Only known if the CPK list has a directory column
+    pub directory: Option<String>,
+    pub file_name: String,
+    pub cpk_name: String,
+}
+
+/// Maps the game's file names to the CPK containing them, as listed in `cpk_list.cfg.bin`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CpkIndex {
+    version: u32,
+    source_size: u64,
+    source_mtime: u64,
+    entries: Vec<CpkIndexEntry>,
+    #[serde(skip)]
+    by_file_name: HashMap<String, Vec<usize>>,
+}
+
+impl CpkIndex {
+    /// Decrypts and parses `cpk_list.cfg.bin`
+    pub fn from_cpk_list(cpk_list_path: &Path) -> io::Result<CpkIndex> {
+        let (source_size, source_mtime) = source_stamp(cpk_list_path)?;
+
+        let cpk_list = CriwareCrypt::new(cpk_list_path)?.decrypt_ram()?;
+        let database = parse_database(&cpk_list)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Unable to parse the CPK list: {e:?}")))?;
+
+        let cpk_table = database.table(CPK_TABLE)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "The CPK list has no CPK_ITEM table"))?;
+
+        // We only keep the first string of each column
+        let mut rows: Vec<Vec<Option<&str>>> = Vec::new();
+        for row in cpk_table.rows() {
+            rows.push(row.values.iter()
+                .map(|value| match value.first() {
+                    Some(Value::String(s)) => Some(s.as_str()),
+                    _ => None,
+                })
+                .collect()
+            );
+        }
+
+        let schema = CpkListSchema::detect(&rows)?;
+
+        let entries = rows.iter()
+            .map(|row| CpkIndexEntry {
+                directory: schema.directory.map(|column| normalize_path(row[column].unwrap())),
+                file_name: row[schema.file_name].unwrap().to_string(),
+                cpk_name: row[schema.cpk_name].unwrap().to_string(),
+            })
+            .collect();
+
+        Ok(CpkIndex::new(entries, source_size, source_mtime))
+    }
+
+    /// Index of the entries of a CPK list with this size and modification time
+    pub(crate) fn new(entries: Vec<CpkIndexEntry>, source_size: u64, source_mtime: u64) -> CpkIndex {
+        let mut index = CpkIndex {
+            version: CACHE_VERSION,
+            source_size,
+            source_mtime,
+            entries,
+            by_file_name: HashMap::new(),
+        };
+        index.build_lookup();
+
+        index
+    }
+
+    /// Loads the index from `cache_path` if it is still up to date with `cpk_list.cfg.bin`,
+    /// otherwise parses the CPK list again and refreshes the cache
+    pub fn load(cpk_list_path: &Path, cache_path: &Path) -> io::Result<CpkIndex> {
+        if let Some(index) = Self::load_cache(cpk_list_path, cache_path) {
+            return Ok(index);
+        }
+
+        let index = Self::from_cpk_list(cpk_list_path)?;
+
+        // A cache that can't be written is not worth failing over
+        let _ = index.save(cache_path);
+
+        Ok(index)
+    }
+
+    pub fn save(&self, cache_path: &Path) -> io::Result<()> {
+        if let Some(folder) = cache_path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+
+        let writer = BufWriter::new(File::create(cache_path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Whether the entries know the directory of their file
+    pub fn has_directories(&self) -> bool {
+        self.entries.iter().all(|entry| entry.directory.is_some())
+    }
+
+    pub fn entries(&self) -> &[CpkIndexEntry] {
+        &self.entries
+    }
+
+    /// Every entry for a file called exactly `file_name`
+    pub fn find(&self, file_name: &str) -> impl Iterator<Item = &CpkIndexEntry> {
+        self.by_file_name.get(file_name)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.entries[i])
+    }
+
+    /// Every entry whose file name satisfies `predicate`
+    pub fn filter<P: FnMut(&str) -> bool>(&self, mut predicate: P) -> impl Iterator<Item = &CpkIndexEntry> {
+        self.entries.iter().filter(move |entry| predicate(&entry.file_name))
+    }
+
+    fn load_cache(cpk_list_path: &Path, cache_path: &Path) -> Option<CpkIndex> {
+        let reader = BufReader::new(File::open(cache_path).ok()?);
+        let mut index: CpkIndex = serde_json::from_reader(reader).ok()?;
+
+        let (source_size, source_mtime) = source_stamp(cpk_list_path).ok()?;
+        if index.version != CACHE_VERSION || index.source_size != source_size || index.source_mtime != source_mtime {
+            return None;
+        }
+
+        index.build_lookup();
+        Some(index)
+    }
+
+    fn build_lookup(&mut self) {
+        self.by_file_name.clear();
+        for (i, entry) in self.entries.iter().enumerate() {
+            self.by_file_name.entry(entry.file_name.clone()).or_default().push(i);
+        }
+    }
+}
+
+/// Size and modification time (in nanoseconds since the epoch) of a file
+pub(crate) fn source_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+
+    Ok((metadata.len(), mtime))
+}
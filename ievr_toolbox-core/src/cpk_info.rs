@@ -0,0 +1,35 @@
+use serde::Serialize;
+
+/// Location of one of the tables referenced by the CPK header
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TableLocation {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Fields read from the master `CPK ` header table
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CpkHeader {
+    pub version: Option<u64>,
+    pub revision: Option<u64>,
+    pub alignment: Option<u64>,
+    pub codec: Option<u64>,
+    pub content: Option<TableLocation>,
+    pub toc: Option<TableLocation>,
+    pub itoc: Option<TableLocation>,
+    pub etoc: Option<TableLocation>,
+    pub gtoc: Option<TableLocation>,
+}
+
+/// Archive-level statistics of a CPK
+#[derive(Debug, Clone, Serialize)]
+pub struct CpkInfo {
+    pub header: CpkHeader,
+    pub cpk_size: usize,
+    /// Whether the header or TOC tables were XOR-masked
+    pub masked: bool,
+    pub file_count: usize,
+    pub compressed_count: usize,
+    pub stored_size: u64,
+    pub extracted_size: u64,
+}
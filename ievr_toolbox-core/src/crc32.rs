@@ -0,0 +1,56 @@
+use std::io::{self, Read};
+
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+/// Size of the buffer through which [`crc32_reader`] reads
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+pub(crate) fn crc32_table() -> [u32; 256] {
+    let mut table: [u32; 256] = [0; 256];
+
+    for i in 0..256 {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ POLYNOMIAL;
+            } else {
+                crc >>= 1;
+            }
+        }
+        table[i] = crc;
+    }
+
+    table
+}
+
+/// Standard CRC-32 (IEEE) of a buffer, as used by zip and most checksum tools
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// CRC-32 of everything `reader` returns, read in chunks so that big files are never held in RAM
+pub fn crc32_reader(mut reader: impl Read) -> io::Result<u32> {
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut crc = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(crc),
+            Ok(read) => crc = crc32_update(crc, &buffer[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// CRC-32 of the bytes hashed into `crc` followed by `data`, to hash data read in several parts
+pub(crate) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(crc32_table);
+
+    let mut crc = !crc;
+    for &b in data {
+        crc = (crc >> 8) ^ table[((crc ^ b as u32) & 0xFF) as usize];
+    }
+
+    !crc
+}
@@ -1,11 +1,16 @@
 use std::{fs::File, io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::Path};
 
+use crate::{CancellationToken, DumpEvent, IoThrottle, ProgressSink, SilentProgress, crc32::crc32_table};
+
 const BUFFER_SIZE: usize = 8 * 1024 * 1024; // 8 MB
 
 pub struct CriwareCrypt {
     input_file: File,
     keys: [u8; 4],
     crc32table: [u32; 256],
+    encrypted: Option<bool>,
+    cancellation: CancellationToken,
+    throttle: IoThrottle,
 }
 
 impl CriwareCrypt {
@@ -13,26 +18,45 @@ impl CriwareCrypt {
         let input_file = File::open(path)?; 
         let filename = path.file_name().unwrap().to_str().unwrap();
 
-        let crc32table = Self::initialize_table();
+        let crc32table = crc32_table();
         let keys = Self::compute_key(filename, &crc32table);
 
         Ok(CriwareCrypt { 
             input_file,
             keys, 
             crc32table, 
+            encrypted: None,
+            cancellation: CancellationToken::new(),
+            throttle: IoThrottle::unlimited(),
         })   
     }
 
+    /// Stops the decryption and encryption with an [`std::io::ErrorKind::Interrupted`] error once `cancellation` is cancelled
+    pub fn with_cancellation(mut self, cancellation: &CancellationToken) -> Self {
+        self.cancellation = cancellation.clone();
+        self
+    }
+
+    /// Counts the bytes read and written by the decryption and encryption against `throttle`
+    pub fn with_throttle(mut self, throttle: &IoThrottle) -> Self {
+        self.throttle = throttle.clone();
+        self
+    }
+
     pub fn decrypt(&mut self, output_file: &mut File) -> Result<(), std::io::Error> {
+        self.decrypt_with_progress(output_file, &SilentProgress)
+    }
+
+    /// Like [`decrypt`](Self::decrypt), sending the number of bytes done to `progress` after each part
+    pub fn decrypt_with_progress(&mut self, output_file: &mut File, progress: &dyn ProgressSink) -> Result<(), std::io::Error> {
+        let total = self.input_file.metadata()?.len();
+
         let mut header = [0u8; 4];
         self.input_file.read_exact(&mut header)?;
         self.input_file.seek(SeekFrom::Start(0))?;
 
-        // If already decrypted, just copy
-        if &header == b"CPK " {
-            std::io::copy(&mut self.input_file, output_file)?;
-            return Ok(());
-        }
+        // An already decrypted file is only copied
+        let encrypted = &header != b"CPK ";
 
         let file = self.input_file.try_clone().unwrap();
 
@@ -43,15 +67,23 @@ impl CriwareCrypt {
         let mut offset: u64 = 0;
 
         loop {
+            self.cancellation.check()?;
+
             let bytes_read = reader.read(&mut buffer)?;
             if bytes_read == 0 {
                 break;
             }
 
-            self.block_cipher(&mut buffer[..bytes_read], offset);
+            // The bytes are read, then written
+            self.throttle.consume(2 * bytes_read);
+
+            if encrypted {
+                self.block_cipher(&mut buffer[..bytes_read], offset);
+            }
             writer.write_all(&buffer[..bytes_read])?;
 
             offset += bytes_read as u64;
+            progress.event(DumpEvent::CryptProgress { done: offset, total });
         }
 
         Ok(())
@@ -63,19 +95,84 @@ impl CriwareCrypt {
         let size = self.input_file.metadata()?.len() as usize;
 
         let mut buffer = Vec::with_capacity(size);
-        reader.read_to_end(&mut buffer)?;
+        if self.throttle.is_limited() {
+            // Read in parts, so that the reads are spread instead of all waiting for the throttle at once
+            loop {
+                let bytes_read = (&mut reader).take(BUFFER_SIZE as u64).read_to_end(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                self.throttle.consume(bytes_read);
+            }
+        } else {
+            reader.read_to_end(&mut buffer)?;
+        }
 
         // If already decrypted, just copy
         if &buffer[0..4] == b"CPK " {
             return Ok(buffer);
         }
 
-        self.block_cipher(&mut buffer, 0);
+        // Decrypted in parts, so that a cancellation doesn't wait for the whole CPK
+        for (index, part) in buffer.chunks_mut(BUFFER_SIZE).enumerate() {
+            self.cancellation.check()?;
+            self.block_cipher(part, (index * BUFFER_SIZE) as u64);
+        }
+        Ok(buffer)
+
+    }
+
+    /// Decrypts only `len` bytes starting at `offset`, without touching the rest of the file
+    pub fn decrypt_range(&mut self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let file_len = self.input_file.metadata()?.len();
+        let end = offset + len as u64;
+
+        if end > file_len {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Range out of the file bounds"));
+        }
+
+        if !self.is_encrypted()? {
+            let mut buffer = vec![0u8; len];
+            self.input_file.seek(SeekFrom::Start(offset))?;
+            self.input_file.read_exact(&mut buffer)?;
+            return Ok(buffer);
+        }
+
+        // The key stream works on 4-byte blocks, so we decrypt the aligned range around it
+        let aligned_start = offset & !3;
+        let aligned_end = ((end + 3) & !3).min(file_len);
+
+        let mut buffer = vec![0u8; (aligned_end - aligned_start) as usize];
+        self.input_file.seek(SeekFrom::Start(aligned_start))?;
+        self.input_file.read_exact(&mut buffer)?;
+
+        self.block_cipher(&mut buffer, aligned_start);
+
+        let start = (offset - aligned_start) as usize;
+        buffer.truncate(start + len);
+        buffer.drain(..start);
+
         Ok(buffer)
+    }
+
+    fn is_encrypted(&mut self) -> std::io::Result<bool> {
+        if let Some(encrypted) = self.encrypted {
+            return Ok(encrypted);
+        }
+
+        let mut header = [0u8; 4];
+        self.input_file.seek(SeekFrom::Start(0))?;
+        self.input_file.read_exact(&mut header)?;
 
+        let encrypted = &header != b"CPK ";
+        self.encrypted = Some(encrypted);
+        Ok(encrypted)
     }
 
-    pub fn encrypt(&mut self, output_file: &mut File) -> Result<(), std::io::Error> {
+    /// Encrypts the file, sending the number of bytes done to `progress` after each part
+    pub fn encrypt(&mut self, output_file: &mut File, progress: &dyn ProgressSink) -> Result<(), std::io::Error> {
+        let total = self.input_file.metadata()?.len();
+
         let file = self.input_file.try_clone().unwrap();
 
         let mut reader = BufReader::with_capacity(BUFFER_SIZE, file);
@@ -85,15 +182,20 @@ impl CriwareCrypt {
         let mut offset: u64 = 0;
 
         loop {
+            self.cancellation.check()?;
+
             let bytes_read = reader.read(&mut buffer)?;
             if bytes_read == 0 {
                 break;
             }
 
+            self.throttle.consume(2 * bytes_read);
+
             self.block_cipher(&mut buffer[..bytes_read], offset);
             writer.write_all(&buffer[..bytes_read])?;
 
             offset += bytes_read as u64;
+            progress.event(DumpEvent::CryptProgress { done: offset, total });
         }
 
         Ok(())
@@ -135,25 +237,6 @@ impl CriwareCrypt {
         }
     }
 
-    fn initialize_table() -> [u32; 256] {
-        let mut table: [u32; 256] = [0; 256];
-        let polynomial = 0xEDB88320;
-
-        for i in 0..256 {
-            let mut crc = i as u32;
-            for _ in 0..8 {
-                if crc & 1 != 0 {
-                    crc = (crc >> 1) ^ polynomial;
-                } else {
-                    crc >>= 1;
-                }
-            }
-            table[i] = crc;
-        }
-
-        table
-    }
-
     fn compute_key(filename: &str, table: &[u32; 256]) -> [u8; 4] {
         let mut crc: u32 = 0xFFFF_FFFF;
 
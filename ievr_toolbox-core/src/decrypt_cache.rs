@@ -0,0 +1,312 @@
+use std::{
+    ffi::OsString, fs::{self, File, OpenOptions}, io::{self, BufReader, Read, Seek, SeekFrom}, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CancellationToken, DecryptionPath, IoThrottle, cpk_index::source_stamp, crc32, criware_crypt::CriwareCrypt};
+
+/// Number of bytes hashed at the start of the CPK, and compared at both ends of the decrypted copy
+const HEADER_SIZE: u64 = 0x800;
+
+/// Extension of the file describing a cache entry, next to the decrypted CPK
+const ENTRY_EXTENSION: &str = "cache.json";
+
+/// Extension of a copy being decrypted, renamed once complete
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// Subfolder of the temp folder holding the cache, which nothing else writes to
+const CACHE_FOLDER: &str = "decrypt_cache";
+
+/// What a decrypted copy was made from. A copy is only valid for a source with the same key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CacheKey {
+    source: PathBuf,
+    size: u64,
+    mtime: u64,
+    header_hash: u32,
+}
+
+impl CacheKey {
+    fn of(source: &Path) -> io::Result<CacheKey> {
+        let (size, mtime) = source_stamp(source)?;
+
+        let mut header = Vec::new();
+        File::open(source)?.take(HEADER_SIZE).read_to_end(&mut header)?;
+
+        Ok(CacheKey { source: source.to_path_buf(), size, mtime, header_hash: crc32(&header) })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryInfo {
+    key: CacheKey,
+    /// Seconds since the epoch, used to evict the least recently used entries first
+    last_used: u64,
+}
+
+/// A decrypted CPK kept in the cache
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub source: PathBuf,
+    pub size: u64,
+    pub last_used: u64,
+}
+
+/// Folder keeping the decrypted copies of big CPKs between runs. Every copy is described
+/// by an entry written once the decryption completed, and is only reused while its source
+/// has the same size, modification time and header, and the ends of the copy still decrypt
+/// from the source.
+///
+/// The copies are kept in the `decrypt_cache` subfolder of the temp folder, so that a temp folder
+/// shared with other files, or even the game folder, never has its own files replaced or removed.
+#[derive(Debug, Clone)]
+pub struct DecryptCache {
+    folder: PathBuf,
+}
+
+impl DecryptCache {
+    /// The cache of the temp folder `temp_folder`
+    pub fn new(temp_folder: impl AsRef<Path>) -> DecryptCache {
+        DecryptCache { folder: temp_folder.as_ref().join(CACHE_FOLDER) }
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    /// Location of the decrypted copy of `source`, named after the CPK and a hash of its full path,
+    /// as CPKs in different folders may have the same name
+    pub fn file_path(&self, source: &Path) -> PathBuf {
+        let full_path = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        let path_hash = crc32(full_path.to_string_lossy().as_bytes());
+
+        let mut file_name = OsString::from(format!("{path_hash:08x}_"));
+        file_name.push(source.file_name().unwrap_or_default());
+        self.folder.join(file_name)
+    }
+
+    /// Whether the cache holds a valid decrypted copy of `source`
+    pub fn contains(&self, source: &Path) -> bool {
+        CacheKey::of(source).is_ok_and(|key| self.open_valid(&key).is_some())
+    }
+
+    /// Opens the decrypted copy of `source`, decrypting it first unless the cache
+    /// already holds a valid one, and tells which of the two happened.
+    /// A decryption that fails or is cancelled leaves nothing behind.
+    /// The decryption is counted against `throttle`.
+    pub fn open(&self, source: &Path, cancellation: &CancellationToken, throttle: &IoThrottle) -> io::Result<(File, DecryptionPath)> {
+        let key = CacheKey::of(source)?;
+        let file_path = self.file_path(source);
+        let entry_path = entry_path(&file_path);
+
+        if let Some(file) = self.open_valid(&key) {
+            write_entry(&entry_path, &EntryInfo { key, last_used: now() })?;
+            return Ok((file, DecryptionPath::Cached));
+        }
+
+        // The entry goes first, so that a decryption cut short never looks valid
+        remove_if_exists(&entry_path)?;
+        fs::create_dir_all(&self.folder)?;
+
+        // The copy is only given its name once complete, and is left as a partial copy otherwise
+        let partial_path = partial_path(&file_path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&partial_path)?;
+
+        let mut crypt = CriwareCrypt::new(source)?
+            .with_cancellation(cancellation)
+            .with_throttle(throttle);
+        if let Err(e) = crypt.decrypt(&mut file) {
+            drop(file);
+            remove_if_exists(&partial_path)?;
+            return Err(e);
+        }
+        file.rewind()?;
+        fs::rename(&partial_path, &file_path)?;
+
+        write_entry(&entry_path, &EntryInfo { key, last_used: now() })?;
+
+        Ok((file, DecryptionPath::Temp))
+    }
+
+    /// Removes the decrypted copy of `source` and its entry
+    pub fn remove(&self, source: &Path) -> io::Result<()> {
+        let file_path = self.file_path(source);
+        remove_if_exists(&entry_path(&file_path))?;
+        remove_if_exists(&file_path)
+    }
+
+    /// The decrypted CPKs of the cache, least recently used first
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        if !self.folder.is_dir() {
+            return Ok(entries);
+        }
+
+        for dir_entry in fs::read_dir(&self.folder)? {
+            let entry_path = dir_entry?.path();
+            let Some(file_path) = decrypted_path(&entry_path) else {
+                continue;
+            };
+
+            let (Some(info), Ok(metadata)) = (read_entry(&entry_path), fs::metadata(&file_path)) else {
+                continue;
+            };
+
+            entries.push(CacheEntry {
+                path: file_path,
+                source: info.key.source,
+                size: metadata.len(),
+                last_used: info.last_used,
+            });
+        }
+
+        entries.sort_by_key(|entry| entry.last_used);
+        Ok(entries)
+    }
+
+    /// Total size of the decrypted CPKs of the cache
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.entries()?.iter().map(|entry| entry.size).sum())
+    }
+
+    /// Removes the copies whose source changed or disappeared, or that aren't named after their source,
+    /// the entries whose copy is missing, and the partial copies left by an interrupted decryption. Returns the number of bytes freed.
+    pub fn clean(&self) -> io::Result<u64> {
+        let mut freed = 0;
+        if !self.folder.is_dir() {
+            return Ok(freed);
+        }
+
+        for dir_entry in fs::read_dir(&self.folder)? {
+            let path = dir_entry?.path();
+
+            if let Some(file_path) = decrypted_path(&path) {
+                let is_current = read_entry(&path).is_some_and(|info| {
+                    file_path.is_file()
+                        && file_path == self.file_path(&info.key.source)
+                        && CacheKey::of(&info.key.source).is_ok_and(|key| key == info.key)
+                });
+
+                if !is_current {
+                    freed += fs::metadata(&file_path).map(|metadata| metadata.len()).unwrap_or_default();
+                    remove_if_exists(&path)?;
+                    remove_if_exists(&file_path)?;
+                }
+            } else if path.extension().is_some_and(|extension| extension == PARTIAL_EXTENSION) {
+                freed += fs::metadata(&path)?.len();
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(freed)
+    }
+
+    /// Removes the least recently used copies until the cache fits in `max_size` bytes.
+    /// Returns the number of bytes freed.
+    pub fn evict(&self, max_size: u64) -> io::Result<u64> {
+        let entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut freed = 0;
+
+        for entry in entries {
+            if size <= max_size {
+                break;
+            }
+
+            remove_if_exists(&entry_path(&entry.path))?;
+            remove_if_exists(&entry.path)?;
+            size -= entry.size;
+            freed += entry.size;
+        }
+
+        Ok(freed)
+    }
+
+    /// Opens the copy described by the entry of `key.source` if it was made from
+    /// this exact source and both its ends still match the decrypted source
+    fn open_valid(&self, key: &CacheKey) -> Option<File> {
+        let file_path = self.file_path(&key.source);
+        let info = read_entry(&entry_path(&file_path))?;
+        if info.key != *key {
+            return None;
+        }
+
+        let mut file = File::open(&file_path).ok()?;
+        if file.metadata().ok()?.len() != key.size {
+            return None;
+        }
+
+        let mut crypt = CriwareCrypt::new(&key.source).ok()?;
+        let len = HEADER_SIZE.min(key.size);
+        for offset in [0, key.size - len] {
+            let mut cached = vec![0u8; len as usize];
+            file.seek(SeekFrom::Start(offset)).ok()?;
+            file.read_exact(&mut cached).ok()?;
+
+            if crypt.decrypt_range(offset, len as usize).ok()? != cached {
+                return None;
+            }
+        }
+
+        file.rewind().ok()?;
+        Some(file)
+    }
+}
+
+fn entry_path(file_path: &Path) -> PathBuf {
+    with_suffix(file_path, ENTRY_EXTENSION)
+}
+
+/// The decrypted CPK described by the entry at `path`, if `path` is an entry
+fn decrypted_path(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let cpk_name = file_name.strip_suffix(ENTRY_EXTENSION)?.strip_suffix('.')?;
+    Some(path.with_file_name(cpk_name))
+}
+
+fn partial_path(file_path: &Path) -> PathBuf {
+    with_suffix(file_path, PARTIAL_EXTENSION)
+}
+
+/// `file_path` with `.extension` appended, keeping its own extension
+fn with_suffix(file_path: &Path, extension: &str) -> PathBuf {
+    let mut path = OsString::from(file_path.as_os_str());
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn read_entry(path: &Path) -> Option<EntryInfo> {
+    let file = File::open(path).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+fn write_entry(path: &Path, info: &EntryInfo) -> io::Result<()> {
+    // Written aside then renamed, so a reader never sees half an entry
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, serde_json::to_vec(info)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
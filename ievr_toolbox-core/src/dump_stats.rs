@@ -0,0 +1,164 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+use serde::{Serialize, Serializer};
+
+use crate::{CpkFile, PoolStats};
+
+/// How a CPK was decrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecryptionPath {
+    /// Smaller than the in-RAM threshold, decrypted in RAM
+    Ram,
+    /// Decrypted to the temp folder
+    Temp,
+    /// A valid decrypted copy was already in the temp folder
+    Cached,
+}
+
+/// What the decryption and the extraction of one CPK took
+#[derive(Debug, Clone, Serialize)]
+pub struct CpkStats {
+    pub name: String,
+    pub size: u64,
+    pub decryption: DecryptionPath,
+    #[serde(rename = "decrypt_secs", serialize_with = "as_secs")]
+    pub decrypt_time: Duration,
+    pub file_count: usize,
+    /// Bytes of the extracted files in the CPK, compressed or not
+    pub stored_size: u64,
+    pub extracted_size: u64,
+    /// Time spent writing its files, summed over the threads
+    #[serde(rename = "decompress_secs", serialize_with = "as_secs")]
+    pub decompress_time: Duration,
+}
+
+impl CpkStats {
+    /// Bytes of the CPK decrypted per second. For a cached copy, this only measures its checks.
+    pub fn decrypt_throughput(&self) -> f64 {
+        throughput(self.size, self.decrypt_time)
+    }
+
+    /// Extracted bytes written per second
+    pub fn decompress_throughput(&self) -> f64 {
+        throughput(self.extracted_size, self.decompress_time)
+    }
+}
+
+/// What a stage of the dump took for every CPK together
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StageStats {
+    /// Time spent in the stage, summed over the threads
+    #[serde(rename = "secs", serialize_with = "as_secs")]
+    pub time: Duration,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl StageStats {
+    /// Bytes written per second
+    pub fn throughput(&self) -> f64 {
+        throughput(self.bytes_written, self.time)
+    }
+}
+
+/// Where the time of a finished [`DumpPipeline`](crate::DumpPipeline) went
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PerformanceReport {
+    /// The CPKs in the order they were decrypted
+    pub cpks: Vec<CpkStats>,
+    /// Decryption of the CPKs, in RAM or to the temp folder. Cached copies are neither read nor written.
+    pub decryption: StageStats,
+    /// Decompression and writing of the extracted files
+    pub decompression: StageStats,
+    pub memory: PoolStats,
+}
+
+/// Collects the timings of a running [`DumpPipeline`](crate::DumpPipeline), for every CPK.
+/// The handle can be cloned and read once the dump is done.
+#[derive(Debug, Clone, Default)]
+pub struct DumpStats {
+    inner: Arc<Mutex<Collected>>,
+}
+
+#[derive(Debug, Default)]
+struct Collected {
+    cpks: Vec<CpkStats>,
+    /// Index of each CPK in `cpks`, by name
+    indices: HashMap<Arc<str>, usize>,
+    memory: PoolStats,
+}
+
+impl DumpStats {
+    pub fn report(&self) -> PerformanceReport {
+        let collected = self.inner.lock().unwrap();
+
+        let mut report = PerformanceReport {
+            cpks: collected.cpks.clone(),
+            memory: collected.memory,
+            ..PerformanceReport::default()
+        };
+
+        for cpk in &collected.cpks {
+            report.decryption.time += cpk.decrypt_time;
+            if cpk.decryption != DecryptionPath::Cached {
+                report.decryption.bytes_read += cpk.size;
+                report.decryption.bytes_written += cpk.size;
+            }
+
+            report.decompression.time += cpk.decompress_time;
+            report.decompression.bytes_read += cpk.stored_size;
+            report.decompression.bytes_written += cpk.extracted_size;
+        }
+
+        report
+    }
+
+    pub(crate) fn record_decryption(&self, cpk_name: &Arc<str>, size: u64, decryption: DecryptionPath, time: Duration) {
+        let mut collected = self.inner.lock().unwrap();
+
+        let index = collected.cpks.len();
+        collected.indices.insert(cpk_name.clone(), index);
+        collected.cpks.push(CpkStats {
+            name: cpk_name.to_string(),
+            size,
+            decryption,
+            decrypt_time: time,
+            file_count: 0,
+            stored_size: 0,
+            extracted_size: 0,
+            decompress_time: Duration::ZERO,
+        });
+    }
+
+    pub(crate) fn record_file(&self, file: &CpkFile, time: Duration) {
+        let mut collected = self.inner.lock().unwrap();
+
+        let Some(&index) = file.cpk_name.as_ref().and_then(|cpk_name| collected.indices.get(cpk_name)) else {
+            return;
+        };
+
+        let cpk = &mut collected.cpks[index];
+        cpk.file_count += 1;
+        cpk.stored_size += file.file_size as u64;
+        cpk.extracted_size += file.extract_size as u64;
+        cpk.decompress_time += time;
+    }
+
+    pub(crate) fn set_memory(&self, memory: PoolStats) {
+        self.inner.lock().unwrap().memory = memory;
+    }
+}
+
+fn throughput(bytes: u64, time: Duration) -> f64 {
+    if time.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / time.as_secs_f64()
+    }
+}
+
+/// Durations are written as seconds in JSON
+pub(crate) fn as_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
@@ -0,0 +1,165 @@
+use std::{collections::{HashMap, HashSet}, io, path::{Path, PathBuf}};
+
+use serde::Serialize;
+
+use crate::{Decompressor, GameIndex, GameIndexMatch, IndexedFile, Overrides, criware_crypt::CriwareCrypt, extracted_crc32, read_file_with};
+
+/// How a file differs between two versions of the game
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A file that differs between two versions of the game. The hashes are the
+/// CRC32 of the extracted files, only known once the contents were compared.
+#[derive(Debug, Clone)]
+pub struct FileChange<'a> {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub old: Option<GameIndexMatch<'a>>,
+    pub new: Option<GameIndexMatch<'a>>,
+    pub old_crc32: Option<u32>,
+    pub new_crc32: Option<u32>,
+}
+
+impl<'a> FileChange<'a> {
+    fn new(path: String, kind: ChangeKind, old: Option<GameIndexMatch<'a>>, new: Option<GameIndexMatch<'a>>) -> Self {
+        Self { path, kind, old, new, old_crc32: None, new_crc32: None }
+    }
+
+    /// Difference between the extracted sizes of the new and old versions
+    pub fn size_delta(&self) -> i64 {
+        let size = |found: Option<GameIndexMatch>| found.map_or(0, |found| found.file.extract_size as i64);
+        size(self.new) - size(self.old)
+    }
+}
+
+/// Differences between the TOCs of two versions of the game
+#[derive(Debug)]
+pub struct GameDiff<'a> {
+    changes: Vec<FileChange<'a>>,
+    /// Files with the same sizes in both versions, which may still have a different content
+    same_size: Vec<(GameIndexMatch<'a>, GameIndexMatch<'a>)>,
+}
+
+impl<'a> GameDiff<'a> {
+    /// Compares the TOCs of both versions, only looking at the files accepted by `filter`.
+    /// A file in several CPKs is compared through the copy each version loads, as chosen by its `Overrides`.
+    /// Files whose sizes didn't change are only reported once [`compare_contents`](Self::compare_contents) is called.
+    pub fn compare(
+        old: &'a GameIndex,
+        new: &'a GameIndex,
+        old_overrides: &Overrides,
+        new_overrides: &Overrides,
+        mut filter: impl FnMut(&IndexedFile) -> bool,
+    ) -> GameDiff<'a> {
+        let mut changes = Vec::new();
+        let mut same_size = Vec::new();
+        let mut seen = HashSet::new();
+
+        for old_match in old.files().filter(|found| filter(found.file)) {
+            let path = old_match.file.path();
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            let old_match = old_overrides.resolve(old, &path).unwrap_or(old_match);
+
+            match new_overrides.resolve(new, &path) {
+                None => changes.push(FileChange::new(path, ChangeKind::Removed, Some(old_match), None)),
+                Some(new_match) => {
+                    if old_match.file.file_size != new_match.file.file_size
+                        || old_match.file.extract_size != new_match.file.extract_size
+                    {
+                        changes.push(FileChange::new(path, ChangeKind::Modified, Some(old_match), Some(new_match)));
+                    } else {
+                        same_size.push((old_match, new_match));
+                    }
+                }
+            }
+        }
+
+        for new_match in new.files().filter(|found| filter(found.file)) {
+            let path = new_match.file.path();
+            if seen.insert(path.clone()) {
+                let new_match = new_overrides.resolve(new, &path).unwrap_or(new_match);
+                changes.push(FileChange::new(path, ChangeKind::Added, None, Some(new_match)));
+            }
+        }
+
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        GameDiff { changes, same_size }
+    }
+
+    /// Hashes the changed files and the files whose sizes didn't change, reading them
+    /// from the CPKs of both versions, to find the ones modified without being resized
+    pub fn compare_contents(&mut self) -> io::Result<()> {
+        // Every copy is read CPK by CPK, so that each CPK is only opened once
+        let mut copies: Vec<GameIndexMatch> = self.changes.iter()
+            .flat_map(|change| change.old.into_iter().chain(change.new))
+            .chain(self.same_size.iter().flat_map(|&(old_match, new_match)| [old_match, new_match]))
+            .collect();
+        copies.sort_by(|a, b| (&a.cpk.path, a.file.file_offset).cmp(&(&b.cpk.path, b.file.file_offset)));
+
+        let mut reader = ContentReader::default();
+        let mut crc32s = HashMap::new();
+        for copy in copies {
+            crc32s.insert(copy_key(copy), reader.crc32(copy)?);
+        }
+
+        for change in &mut self.changes {
+            change.old_crc32 = change.old.map(|found| crc32s[&copy_key(found)]);
+            change.new_crc32 = change.new.map(|found| crc32s[&copy_key(found)]);
+        }
+
+        for (old_match, new_match) in std::mem::take(&mut self.same_size) {
+            let old_crc32 = crc32s[&copy_key(old_match)];
+            let new_crc32 = crc32s[&copy_key(new_match)];
+
+            if old_crc32 != new_crc32 {
+                let mut change = FileChange::new(old_match.file.path(), ChangeKind::Modified, Some(old_match), Some(new_match));
+                change.old_crc32 = Some(old_crc32);
+                change.new_crc32 = Some(new_crc32);
+                self.changes.push(change);
+            }
+        }
+
+        self.changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(())
+    }
+
+    pub fn changes(&self) -> &[FileChange<'a>] {
+        &self.changes
+    }
+
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.changes.iter().filter(|change| change.kind == kind).count()
+    }
+}
+
+/// Identifies a copy of a file by its CPK and its place in it
+fn copy_key<'a>(found: GameIndexMatch<'a>) -> (&'a Path, u64) {
+    (&found.cpk.path, found.file.file_offset)
+}
+
+/// Hashes the extracted content of files, keeping the CPK of the last one open
+#[derive(Default)]
+struct ContentReader {
+    decompressor: Decompressor,
+    crypt: Option<(PathBuf, CriwareCrypt)>,
+}
+
+impl ContentReader {
+    fn crc32(&mut self, found: GameIndexMatch) -> io::Result<u32> {
+        let crypt = match &mut self.crypt {
+            Some((path, crypt)) if *path == found.cpk.path => crypt,
+            crypt => &mut crypt.insert((found.cpk.path.clone(), CriwareCrypt::new(&found.cpk.path)?)).1,
+        };
+
+        let file = read_file_with(crypt, &found.cpk_file())?;
+        extracted_crc32(&mut self.decompressor, &file)
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap, fs::{self, File}, io::{self, BufReader, BufWriter}, path::{Path, PathBuf}, sync::Arc
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{CpkFile, TocParser, cpk_index::source_stamp, crc32, read_cpk_toc};
+
+/// Bumped whenever the layout of the cache changes
+const CACHE_VERSION: u32 = 2;
+
+/// A file listed in the TOC of a CPK
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedFile {
+    pub directory: String,
+    pub file_name: String,
+    pub file_offset: u64,
+    pub file_size: u32,
+    pub extract_size: u32,
+    pub user_string: Option<String>,
+}
+
+impl IndexedFile {
+    /// Full path of the file in the game, `DirName/FileName`
+    pub fn path(&self) -> String {
+        join_path(&self.directory, &self.file_name)
+    }
+}
+
+/// A CPK of the game folder and its content, with the size and modification
+/// time it had when its TOC was read
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedCpk {
+    pub path: PathBuf,
+    /// Path relative to the data folder, with `/` separators, which tells apart CPKs
+    /// with the same name in different folders
+    pub relative_path: String,
+    pub size: u64,
+    pub mtime: u64,
+    pub files: Vec<IndexedFile>,
+}
+
+impl IndexedCpk {
+    pub fn name(&self) -> String {
+        self.path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+    }
+
+    /// CRC32 of the TOC entries, which changes whenever a file is added, removed,
+    /// moved or resized in the CPK
+    pub fn toc_hash(&self) -> u32 {
+        let mut toc = Vec::new();
+        for file in &self.files {
+            toc.extend_from_slice(file.path().as_bytes());
+            toc.push(0);
+            toc.extend_from_slice(&file.file_offset.to_le_bytes());
+            toc.extend_from_slice(&file.file_size.to_le_bytes());
+            toc.extend_from_slice(&file.extract_size.to_le_bytes());
+        }
+
+        crc32(&toc)
+    }
+}
+
+/// A file found in the index, along with the CPK containing it
+#[derive(Debug, Clone, Copy)]
+pub struct GameIndexMatch<'a> {
+    pub cpk: &'a IndexedCpk,
+    pub file: &'a IndexedFile,
+}
+
+impl GameIndexMatch<'_> {
+    /// The file as a [`CpkFile`] without data, ready for [`read_cpk_file`](crate::read_cpk_file)
+    pub fn cpk_file(&self) -> CpkFile {
+        CpkFile {
+            cpk_name: Some(Arc::from(self.cpk.relative_path.as_str())),
+            cpk_path: Some(Arc::from(self.cpk.path.as_path())),
+            user_string: self.file.user_string.as_deref().map(Arc::from),
+            directory: Some(Arc::from(self.file.directory.as_str())),
+            file_name: self.file.file_name.clone(),
+            file_offset: self.file.file_offset,
+            file_size: self.file.file_size,
+            extract_size: self.file.extract_size,
+            ..Default::default()
+        }
+    }
+}
+
+/// Content of every CPK of the game folder
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GameIndex {
+    version: u32,
+    cpks: Vec<IndexedCpk>,
+    #[serde(skip)]
+    by_path: HashMap<String, (usize, usize)>,
+    #[serde(skip)]
+    by_file_name: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl GameIndex {
+    /// Indexes every CPK of `data_folder`. If `cache_path` holds a previous index,
+    /// only the CPKs whose size or modification time changed have their TOC read again,
+    /// and the cache is refreshed afterwards.
+    pub fn load(data_folder: &Path, cache_path: Option<&Path>) -> io::Result<GameIndex> {
+        let mut cached: HashMap<PathBuf, IndexedCpk> = cache_path
+            .and_then(Self::load_cache)
+            .map(|index| index.cpks.into_iter().map(|cpk| (cpk.path.clone(), cpk)).collect())
+            .unwrap_or_default();
+
+        let mut toc_parser = TocParser::default();
+        let mut cpks = Vec::new();
+        let mut changed = cached.is_empty();
+
+        for cpk_path in find_cpk_files(data_folder)? {
+            let (size, mtime) = source_stamp(&cpk_path)?;
+
+            match cached.remove(&cpk_path) {
+                Some(cpk) if cpk.size == size && cpk.mtime == mtime => cpks.push(cpk),
+                _ => {
+                    cpks.push(index_cpk(data_folder, &cpk_path, size, mtime, &mut toc_parser)?);
+                    changed = true;
+                }
+            }
+        }
+
+        // Any CPK left in the cache has been removed from the game folder
+        changed |= !cached.is_empty();
+
+        let index = GameIndex::new(cpks);
+
+        if let Some(cache_path) = cache_path && changed {
+            // A cache that can't be written is not worth failing over
+            let _ = index.save(cache_path);
+        }
+
+        Ok(index)
+    }
+
+    /// Index of CPKs whose TOC was already read
+    pub fn new(cpks: Vec<IndexedCpk>) -> GameIndex {
+        let mut index = GameIndex { version: CACHE_VERSION, cpks, ..Default::default() };
+        index.build_lookup();
+
+        index
+    }
+
+    /// Opens an index saved by [`GameIndex::save`] as it is, without refreshing it
+    pub fn open(index_path: &Path) -> io::Result<GameIndex> {
+        let mut index = Self::load_cache(index_path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not a valid game index, or saved by another version"))?;
+        index.build_lookup();
+
+        Ok(index)
+    }
+
+    pub fn save(&self, cache_path: &Path) -> io::Result<()> {
+        if let Some(folder) = cache_path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+
+        let writer = BufWriter::new(File::create(cache_path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn cpks(&self) -> &[IndexedCpk] {
+        &self.cpks
+    }
+
+    /// Every file of every CPK
+    pub fn files(&self) -> impl Iterator<Item = GameIndexMatch<'_>> {
+        self.cpks.iter()
+            .flat_map(|cpk| cpk.files.iter().map(move |file| GameIndexMatch { cpk, file }))
+    }
+
+    /// Looks a file up by its full path, such as `data/common/chara_param.cfg.bin`
+    pub fn get(&self, path: &str) -> Option<GameIndexMatch<'_>> {
+        self.by_path.get(&normalize_path(path)).map(|&location| self.at(location))
+    }
+
+    /// Every file called exactly `file_name`, whatever its directory
+    pub fn find_by_name(&self, file_name: &str) -> impl Iterator<Item = GameIndexMatch<'_>> {
+        self.by_file_name.get(file_name)
+            .into_iter()
+            .flatten()
+            .map(|&location| self.at(location))
+    }
+
+    /// Every file whose full path matches `re`
+    pub fn find_regex(&self, re: &Regex) -> impl Iterator<Item = GameIndexMatch<'_>> {
+        self.files().filter(|found| re.is_match(&found.file.path()))
+    }
+
+    fn at(&self, (cpk, file): (usize, usize)) -> GameIndexMatch<'_> {
+        let cpk = &self.cpks[cpk];
+        GameIndexMatch { cpk, file: &cpk.files[file] }
+    }
+
+    fn load_cache(cache_path: &Path) -> Option<GameIndex> {
+        let reader = BufReader::new(File::open(cache_path).ok()?);
+        let index: GameIndex = serde_json::from_reader(reader).ok()?;
+
+        (index.version == CACHE_VERSION).then_some(index)
+    }
+
+    fn build_lookup(&mut self) {
+        self.by_path.clear();
+        self.by_file_name.clear();
+
+        for (cpk_idx, cpk) in self.cpks.iter().enumerate() {
+            for (file_idx, file) in cpk.files.iter().enumerate() {
+                self.by_path.entry(file.path()).or_insert((cpk_idx, file_idx));
+                self.by_file_name.entry(file.file_name.clone()).or_default().push((cpk_idx, file_idx));
+            }
+        }
+    }
+}
+
+fn index_cpk(data_folder: &Path, cpk_path: &Path, size: u64, mtime: u64, toc_parser: &mut TocParser) -> io::Result<IndexedCpk> {
+    let files = read_cpk_toc(cpk_path, toc_parser)?
+        .into_iter()
+        .map(|file| IndexedFile {
+            directory: normalize_path(file.directory.as_deref().unwrap_or_default()),
+            file_name: file.file_name,
+            file_offset: file.file_offset,
+            file_size: file.file_size,
+            extract_size: file.extract_size,
+            user_string: file.user_string.as_deref().map(str::to_string),
+        })
+        .collect();
+
+    Ok(IndexedCpk {
+        path: cpk_path.to_path_buf(),
+        relative_path: relative_cpk_path(data_folder, cpk_path),
+        size,
+        mtime,
+        files,
+    })
+}
+
+/// Lists every CPK archive in the folder and its subfolders
+pub fn find_cpk_files(folder: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut cpk_files = Vec::new();
+    visit_dirs(folder, &mut |path| {
+        if let Some(ext) = path.extension() && ext.to_string_lossy().to_lowercase() == "cpk" {
+            cpk_files.push(path);
+        }
+    })?;
+    Ok(cpk_files)
+}
+
+pub fn visit_dirs(dir: &Path, cb: &mut dyn FnMut(PathBuf)) -> io::Result<()> {
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_dir() {
+                // Recursively call the function for subdirectories
+                visit_dirs(&path, cb)?;
+            } else {
+                // It's a file, execute the callback
+                cb(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Path of a CPK relative to the data folder, with `/` separators.
+/// A CPK outside of the data folder keeps its whole path.
+pub fn relative_cpk_path(data_folder: &Path, cpk_path: &Path) -> String {
+    normalize_path(&cpk_path.strip_prefix(data_folder).unwrap_or(cpk_path).to_string_lossy())
+}
+
+/// Uses forward slashes and strips leading and trailing ones, so that paths
+/// coming from the TOC and from the user can be compared
+pub fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/").trim_matches('/').to_string()
+}
+
+fn join_path(directory: &str, file_name: &str) -> String {
+    if directory.is_empty() {
+        file_name.to_string()
+    } else {
+        format!("{directory}/{file_name}")
+    }
+}
@@ -0,0 +1,65 @@
+use std::{sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+/// Bytes reserved at once, so that a thread waiting for a big read or write
+/// doesn't hold the bandwidth the others could use meanwhile
+const STEP: usize = 1024 * 1024;
+
+/// Limits the bytes read and written per second. Clones share the same limit,
+/// so every thread of a dump together stays under it.
+#[derive(Debug, Clone, Default)]
+pub struct IoThrottle {
+    limit: Option<Arc<Limit>>,
+}
+
+#[derive(Debug)]
+struct Limit {
+    bytes_per_second: f64,
+    /// When the bandwidth reserved so far is used up
+    next_free: Mutex<Instant>,
+}
+
+impl IoThrottle {
+    /// A throttle that never waits
+    pub fn unlimited() -> IoThrottle {
+        IoThrottle::default()
+    }
+
+    /// Limits the reads and writes to `bytes_per_second`, or doesn't limit them if it is 0
+    pub fn new(bytes_per_second: usize) -> IoThrottle {
+        if bytes_per_second == 0 {
+            return IoThrottle::unlimited();
+        }
+
+        IoThrottle {
+            limit: Some(Arc::new(Limit {
+                bytes_per_second: bytes_per_second as f64,
+                next_free: Mutex::new(Instant::now()),
+            })),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// Waits until `bytes` can be read or written without going over the limit
+    pub fn consume(&self, bytes: usize) {
+        let Some(limit) = &self.limit else {
+            return;
+        };
+
+        let mut remaining = bytes;
+        while remaining > 0 {
+            let step = remaining.min(STEP);
+            remaining -= step;
+
+            let start = {
+                let mut next_free = limit.next_free.lock().unwrap();
+                let start = (*next_free).max(Instant::now());
+                *next_free = start + Duration::from_secs_f64(step as f64 / limit.bytes_per_second);
+                start
+            };
+            thread::sleep(start.saturating_duration_since(Instant::now()));
+        }
+    }
+}
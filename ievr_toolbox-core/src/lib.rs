@@ -1,4 +1,4 @@
-use std::{fs::{self, File, OpenOptions}, io::{Seek, Write}, ops::Deref, path::{Path, PathBuf}, sync::Arc};
+use std::{fs::{self, File, OpenOptions}, io::Write, ops::Deref, path::{Path, PathBuf}, sync::Arc};
 
 use memmap2::Mmap;
 
@@ -7,15 +7,46 @@ mod utf_table;
 mod cpk_file;
 mod compression;
 mod toc_parser;
+mod cpk_info;
+mod cancel;
+mod crc32;
+mod decrypt_cache;
+mod cpk_index;
+mod game_index;
+mod game_diff;
+mod memory_budget;
+mod io_throttle;
+mod queue_metrics;
+mod dump_stats;
+mod pipeline;
+mod archive;
+mod work_queue;
+mod progress;
+mod overrides;
+mod selection;
 
 use criware_crypt::CriwareCrypt;
 use utf_table::UTFTable;
-use compression::is_compressed;
-
 pub use crate::{
     toc_parser::TocParser,
-    compression::Decompressor, 
-    cpk_file::CpkFile
+    compression::{Decompressor, is_compressed},
+    cancel::CancellationToken,
+    crc32::{crc32, crc32_reader},
+    decrypt_cache::{CacheEntry, DecryptCache},
+    cpk_index::{CpkIndex, CpkIndexEntry},
+    selection::{FileSelection, InvalidRule, SelectionRules},
+    game_diff::{ChangeKind, FileChange, GameDiff},
+    overrides::Overrides,
+    memory_budget::{MemoryPool, PoolStats},
+    io_throttle::IoThrottle,
+    queue_metrics::{QueueDepths, QueueMetrics},
+    dump_stats::{CpkStats, DecryptionPath, DumpStats, PerformanceReport, StageStats},
+    pipeline::{DumpPipeline, FolderSink, OutputSink},
+    archive::{BlobEntry, BlobSink, TarSink, ZipCompression, ZipSink},
+    progress::{DumpEvent, ProgressSink, SilentProgress, StderrProgress},
+    game_index::{GameIndex, GameIndexMatch, IndexedCpk, IndexedFile, find_cpk_files, normalize_path, relative_cpk_path, visit_dirs},
+    cpk_file::CpkFile,
+    cpk_info::{CpkHeader, CpkInfo, TableLocation},
 };
 
 pub type DecryptedCpk = Arc<CpkData>;
@@ -36,71 +67,60 @@ impl Deref for CpkData {
     }
 }
 
-pub fn dump_cpk(input_path: PathBuf, tmp_folder: &PathBuf, extract_folder: &PathBuf) {
+pub fn dump_cpk(input_path: PathBuf, tmp_folder: &PathBuf, extract_folder: &PathBuf, progress: &dyn ProgressSink) -> std::io::Result<()> {
     let decrypted_cpk = decrypt_cpk(&input_path, &tmp_folder, 256 * 1024 * 1024);
 
     let mut toc_parser = TocParser::default();
-    let extracted_files = extract_cpk_files(decrypted_cpk, &mut toc_parser);
+    let extracted_files = extract_cpk_files(decrypted_cpk, &mut toc_parser, progress)?;
 
     let mut decompressor = Decompressor::default();
 
     for extracted_file in extracted_files {
-        decompress_files(&mut decompressor, &extracted_file, extract_folder);
+        decompress_files(&mut decompressor, &extracted_file, extract_folder)?;
+        progress.event(DumpEvent::FileExtracted(&extracted_file));
     }
+
+    Ok(())
 }
 
-pub fn decrypt_cpk(input_path: &PathBuf, tmp_folder: &PathBuf, size_threshold: usize) -> DecryptedCpk {
-    let mut tmp_file_path = tmp_folder.clone();
-    tmp_file_path.push(input_path.file_name().unwrap());
+pub fn decrypt_cpk(input_path: &Path, tmp_folder: &Path, size_threshold: usize) -> DecryptedCpk {
+    try_decrypt_cpk(input_path, tmp_folder, size_threshold, &CancellationToken::new(), &IoThrottle::unlimited())
+        .expect("Unable to decrypt file")
+        .0
+}
 
-    let mut crypt_file = CriwareCrypt::new(&input_path)
-        .expect("Unable to load the CPK file in the decryption module");
-    
-    let decrypted_cpk = if fs::metadata(input_path).unwrap().len() as usize >= size_threshold {
-        let f = if Path::exists(&tmp_file_path) {
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&tmp_file_path)
-                .expect("Unable to create output file")
-        } else {
-            let mut f = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&tmp_file_path)
-                .expect("Unable to open pre-existing temporary file");
-            
-            crypt_file.decrypt(&mut f)
-                .expect("Unable to decrypt file");
-
-            f.rewind().expect("Unable to rewind output file after decryption"); 
-            f
-        };
-        Arc::new(CpkData::Big(unsafe { Mmap::map(&f).unwrap() }))
+/// Decrypts the CPK in RAM if it is smaller than `size_threshold`, or to the [`DecryptCache`]
+/// in `tmp_folder` otherwise. Stops with an [`std::io::ErrorKind::Interrupted`] error once
+/// `cancellation` is cancelled. The bytes read and written are counted against `throttle`.
+/// Also tells whether the CPK was decrypted in RAM, to the temp folder, or was already there.
+pub fn try_decrypt_cpk(
+    input_path: &Path,
+    tmp_folder: &Path,
+    size_threshold: usize,
+    cancellation: &CancellationToken,
+    throttle: &IoThrottle,
+) -> std::io::Result<(DecryptedCpk, DecryptionPath)> {
+    let decrypted_cpk = if fs::metadata(input_path)?.len() as usize >= size_threshold {
+        let (f, decryption) = DecryptCache::new(tmp_folder).open(input_path, cancellation, throttle)?;
+        (Arc::new(CpkData::Big(unsafe { Mmap::map(&f)? })), decryption)
     } else {
-        Arc::new(CpkData::Small(crypt_file.decrypt_ram().unwrap()))
+        let mut crypt_file = CriwareCrypt::new(input_path)?
+            .with_cancellation(cancellation)
+            .with_throttle(throttle);
+        (Arc::new(CpkData::Small(crypt_file.decrypt_ram()?)), DecryptionPath::Ram)
     };
 
-    // let decrypted_cpk = Arc::new(crypt_file.decrypt_ram().unwrap());
-
-    // let decrypted_cpk = Arc::new(unsafe { Mmap::map(&decrypted_cpk).unwrap() });
-
-    decrypted_cpk
+    Ok(decrypted_cpk)
 }
 
-pub fn extract_cpk_files(decrypted_cpk: DecryptedCpk, toc_parser: &mut TocParser) -> Vec<CpkFile> {
+pub fn extract_cpk_files(decrypted_cpk: DecryptedCpk, toc_parser: &mut TocParser, progress: &dyn ProgressSink) -> std::io::Result<Vec<CpkFile>> {
     // Parse the master master_table
-    let master_table = UTFTable::new(&decrypted_cpk, 0)
-        .expect("Unable to parse the master UTF master_table");
+    let master_table = parse_table(&decrypted_cpk, 0, "master table")?;
 
-    let (toc_offset, content_offset) = toc_parser.find(&master_table)
-        .expect("Unable to find the TOC");
+    let (toc_offset, content_offset) = find_toc(toc_parser, &master_table)?;
 
     // Move the file to the beginning of the TOC master_table to parse it
-    let toc_table = UTFTable::new(&decrypted_cpk, toc_offset as usize)
-        .expect("Unable to parse the TOC");
+    let toc_table = parse_table(&decrypted_cpk, toc_offset, "TOC")?;
 
     let mut extracted_files = toc_parser.read(&toc_table, content_offset);
 
@@ -108,54 +128,168 @@ pub fn extract_cpk_files(decrypted_cpk: DecryptedCpk, toc_parser: &mut TocParser
         file.set_decrypted_cpk(&decrypted_cpk);
         
         if file.file_size > file.extract_size {
-            eprintln!("File {}: error on file size computing", file.file_name)
+            progress.event(DumpEvent::Warning(&format!("File {}: error on file size computing", file.file_name)));
+        }
+    }
+
+    Ok(extracted_files)
+}
+
+pub fn cpk_info(decrypted_cpk: DecryptedCpk, toc_parser: &mut TocParser) -> std::io::Result<CpkInfo> {
+    let master_table = parse_table(&decrypted_cpk, 0, "master table")?;
+
+    let header = toc_parser.header(&master_table);
+
+    let (toc_offset, content_offset) = find_toc(toc_parser, &master_table)?;
+
+    let toc_table = parse_table(&decrypted_cpk, toc_offset, "TOC")?;
+
+    let mut files = toc_parser.read(&toc_table, content_offset);
+
+    let mut info = CpkInfo {
+        header,
+        cpk_size: decrypted_cpk.len(),
+        masked: master_table.masked || toc_table.masked,
+        file_count: files.len(),
+        compressed_count: 0,
+        stored_size: 0,
+        extracted_size: 0,
+    };
+
+    for file in &mut files {
+        file.set_decrypted_cpk(&decrypted_cpk);
+
+        if is_compressed(file) {
+            info.compressed_count += 1;
         }
+        info.stored_size += file.file_size as u64;
+        info.extracted_size += file.extract_size as u64;
     }
 
-    extracted_files
+    Ok(info)
 }
 
-pub fn decompress_files(decompressor: &mut Decompressor, extracted_file: &CpkFile, extract_folder: &PathBuf) {
+/// Reads the file list of a CPK by decrypting only its header and TOC.
+/// The returned files are not backed by any data, use [`read_cpk_file`] to load one.
+pub fn read_cpk_toc(input_path: &Path, toc_parser: &mut TocParser) -> std::io::Result<Vec<CpkFile>> {
+    let mut crypt = CriwareCrypt::new(input_path)?;
+
+    let master_table = read_utf_table(&mut crypt, 0)?;
+
+    let (toc_offset, content_offset) = find_toc(toc_parser, &master_table)?;
+
+    let toc_table = read_utf_table(&mut crypt, toc_offset)?;
+
+    Ok(toc_parser.read(&toc_table, content_offset))
+}
+
+/// Decrypts only the bytes of `file` from the CPK at `input_path`.
+/// The returned file is backed by its own data and can be passed to [`decompress_files`].
+pub fn read_cpk_file(input_path: &Path, file: &CpkFile) -> std::io::Result<CpkFile> {
+    read_file_with(&mut CriwareCrypt::new(input_path)?, file)
+}
+
+/// Like [`read_cpk_file`], with the [`CriwareCrypt`] of a CPK already open
+fn read_file_with(crypt: &mut CriwareCrypt, file: &CpkFile) -> std::io::Result<CpkFile> {
+    let data = crypt.decrypt_range(file.file_offset, file.file_size as usize)?;
+
+    Ok(file.standalone(Arc::new(CpkData::Small(data))))
+}
+
+/// Parses the UTF table at `offset` of a decrypted CPK, naming it in the error
+fn parse_table(decrypted_cpk: &DecryptedCpk, offset: u64, name: &str) -> std::io::Result<UTFTable> {
+    UTFTable::new(decrypted_cpk, offset as usize)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("Unable to parse the {name}: {e}")))
+}
+
+/// Offsets of the TOC and of the content given by the master table, which a damaged
+/// or unsupported CPK may lack
+fn find_toc(toc_parser: &TocParser, master_table: &UTFTable) -> std::io::Result<(u64, u64)> {
+    toc_parser.find(master_table)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Unable to find the TOC"))
+}
+
+fn read_utf_table(crypt: &mut CriwareCrypt, offset: u64) -> std::io::Result<UTFTable> {
+    let header = crypt.decrypt_range(offset, 16)?;
+    let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+
+    let table = crypt.decrypt_range(offset, 16 + size)?;
+
+    UTFTable::new(&Arc::new(CpkData::Small(table)), 0)
+}
+
+/// Writes the file to the extract folder, decompressing it if needed.
+/// Returns the CRC32 of the written file if the decompressor computes checksums.
+pub fn decompress_files(decompressor: &mut Decompressor, extracted_file: &CpkFile, extract_folder: &PathBuf) -> std::io::Result<Option<u32>> {
+    // The stored bytes are read from the CPK, then written extracted
+    decompressor.throttle().consume(extracted_file.file_size as usize + extracted_file.extract_size as usize);
+
     let mut extracted_file_path = extract_folder.clone();
     if let Some(dir) = &extracted_file.directory {
         extracted_file_path.push(dir.as_ref());
     } 
-    fs::create_dir_all(&extracted_file_path).unwrap();
+    fs::create_dir_all(&extracted_file_path)?;
     extracted_file_path.push(&extracted_file.file_name);
     
     if is_compressed(&extracted_file) {
-        decompressor.decompress(&extracted_file_path, &extracted_file)
-            .expect(&format!("Failed to decompress {}", extracted_file_path.to_string_lossy()));
+        decompressor.decompress(&extracted_file_path, &extracted_file).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("Failed to decompress {}: {e}", extracted_file_path.to_string_lossy()))
+        })
     } else {
-        let mut file_handle = File::create(extracted_file_path).unwrap();
-        file_handle.write_all(&mut extracted_file.data().unwrap()).unwrap();
+        let data = extracted_file.data().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} has no data to write", extracted_file.file_name))
+        })?;
+        let mut file_handle = File::create(extracted_file_path)?;
+        file_handle.write_all(data)?;
+
+        Ok(decompressor.checksum().then(|| crc32(data)))
     }
 }
 
-pub fn decrypt(input_path: &Path, output_path: &Path) -> std::io::Result<()> {
-    let mut crypt = CriwareCrypt::new(input_path)?;
+/// Computes the CRC32 the file would have once extracted, without writing it
+pub fn extracted_crc32(decompressor: &mut Decompressor, extracted_file: &CpkFile) -> std::io::Result<u32> {
+    if is_compressed(extracted_file) {
+        Ok(crc32(&decompressor.decompress_to_vec(extracted_file)?))
+    } else {
+        Ok(crc32(extracted_file.data().unwrap_or_default()))
+    }
+}
+
+/// Decrypts the CPK to `output_path`, reporting the bytes done to `progress`.
+/// If `cancellation` is cancelled, the partial output is removed.
+pub fn decrypt(input_path: &Path, output_path: &Path, cancellation: &CancellationToken, progress: &dyn ProgressSink) -> std::io::Result<()> {
+    let mut crypt = CriwareCrypt::new(input_path)?.with_cancellation(cancellation);
 
     let mut output_file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .truncate(true)
-        .open(output_path)
-        .expect("Unable to open pre-existing temporary file");
+        .open(output_path)?;
 
-    crypt.decrypt(&mut output_file)
+    let result = crypt.decrypt_with_progress(&mut output_file, progress);
+    remove_if_cancelled(result, output_path, cancellation)
 }
 
-pub fn encrypt(input_path: &Path, output_path: &Path) -> std::io::Result<()> {
-    let mut crypt = CriwareCrypt::new(input_path)?;
+/// Encrypts the CPK to `output_path`, reporting the bytes done to `progress`.
+/// If `cancellation` is cancelled, the partial output is removed.
+pub fn encrypt(input_path: &Path, output_path: &Path, cancellation: &CancellationToken, progress: &dyn ProgressSink) -> std::io::Result<()> {
+    let mut crypt = CriwareCrypt::new(input_path)?.with_cancellation(cancellation);
 
     let mut output_file = OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
         .truncate(true)
-        .open(output_path)
-        .expect("Unable to open pre-existing temporary file");
+        .open(output_path)?;
 
-    crypt.encrypt(&mut output_file)
+    let result = crypt.encrypt(&mut output_file, progress);
+    remove_if_cancelled(result, output_path, cancellation)
+}
+
+fn remove_if_cancelled(result: std::io::Result<()>, output_path: &Path, cancellation: &CancellationToken) -> std::io::Result<()> {
+    if result.is_err() && cancellation.is_cancelled() {
+        let _ = fs::remove_file(output_path);
+    }
+    result
 }
\ No newline at end of file
@@ -0,0 +1,183 @@
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+/// How often the pool turned a file or a CPK down. A CPK turned down is tried
+/// again later, so the same CPK may be counted several times.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PoolStats {
+    /// Files decompressed through a small buffer because the memory was taken
+    pub decompressions_without_memory: usize,
+    /// Times a CPK wasn't decrypted in RAM because the memory was held back for the files being decompressed
+    pub held_back_for_decompression: usize,
+    /// Times a CPK wasn't decrypted in RAM because the memory budget was used
+    pub memory_full: usize,
+    /// Times a CPK wasn't decrypted to the temp folder because the disk budget was used
+    pub disk_full: usize,
+    /// Times a CPK wasn't decrypted because too many decrypted CPKs were open
+    pub open_cpks_full: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    used: usize,
+    /// Files being decompressed through a small buffer because the memory was taken
+    decompressing_without_memory: usize,
+    reserved_for_decompression: usize,
+    disk_used: usize,
+    open_cpks: usize,
+    stats: PoolStats,
+}
+
+#[derive(Clone)]
+pub struct MemoryPool {
+    inner: Arc<Mutex<State>>,
+    limit: usize,
+    disk_limit: usize,
+    max_open_cpks: usize,
+}
+
+impl MemoryPool {
+    /// `limit` is the memory budget, and `disk_limit` the disk space budget
+    /// for the CPKs decrypted to the temp folder
+    pub fn new(limit: usize, disk_limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(State::default())),
+            limit,
+            disk_limit,
+            max_open_cpks: usize::MAX,
+        }
+    }
+
+    /// Limits the number of decrypted CPKs alive at once, in RAM and in the temp folder together
+    pub fn with_max_open_cpks(mut self, max_open_cpks: usize) -> Self {
+        self.max_open_cpks = max_open_cpks.max(1);
+        self
+    }
+
+    /// Takes `bytes` to decompress a file in RAM if they are free, without waiting: the memory
+    /// may be held by the CPKs whose files are queued, which only free it once their files are written.
+    /// When they aren't free, the file is decompressed without them, and the memory freed in the meantime
+    /// is kept for the files until [`end_decompression_without_memory`](Self::end_decompression_without_memory).
+    pub fn try_acquire_decompression(&self, bytes: usize) -> bool {
+        let mut state = self.inner.lock().unwrap();
+
+        if state.used + bytes > self.limit {
+            state.stats.decompressions_without_memory += 1;
+            state.decompressing_without_memory += 1;
+            state.reserved_for_decompression = state.reserved_for_decompression.max(bytes);
+            return false;
+        }
+
+        state.used += bytes;
+        true
+    }
+
+    /// Ends a decompression turned down by [`try_acquire_decompression`](Self::try_acquire_decompression)
+    pub fn end_decompression_without_memory(&self) {
+        let mut state = self.inner.lock().unwrap();
+
+        state.decompressing_without_memory -= 1;
+        if state.decompressing_without_memory == 0 {
+            state.reserved_for_decompression = 0;
+        }
+    }
+
+    /// Takes `bytes` for a CPK decrypted in RAM if they are free, without waiting.
+    /// Files that couldn't get their memory go first.
+    pub fn try_acquire_decryption(&self, bytes: usize) -> bool {
+        let mut state = self.inner.lock().unwrap();
+
+        if state.open_cpks >= self.max_open_cpks {
+            state.stats.open_cpks_full += 1;
+            return false;
+        }
+        if state.used + bytes > self.limit {
+            state.stats.memory_full += 1;
+            return false;
+        }
+        if state.used + bytes + state.reserved_for_decompression > self.limit {
+            state.stats.held_back_for_decompression += 1;
+            return false;
+        }
+
+        state.open_cpks += 1;
+        state.used += bytes;
+        true
+    }
+
+    /// Frees a CPK decrypted in RAM, taken by [`try_acquire_decryption`](Self::try_acquire_decryption)
+    pub fn release_decryption(&self, bytes: usize) {
+        let mut state = self.inner.lock().unwrap();
+
+        state.open_cpks -= 1;
+        state.used -= bytes;
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.inner.lock().unwrap().used -= bytes;
+    }
+
+    /// Takes `bytes` of disk space for a CPK decrypted to the temp folder if they are free, without waiting
+    pub fn try_acquire_disk(&self, bytes: usize) -> bool {
+        let mut state = self.inner.lock().unwrap();
+
+        if state.open_cpks >= self.max_open_cpks {
+            state.stats.open_cpks_full += 1;
+            return false;
+        }
+        if state.disk_used + bytes > self.disk_limit {
+            state.stats.disk_full += 1;
+            return false;
+        }
+
+        state.open_cpks += 1;
+        state.disk_used += bytes;
+        true
+    }
+
+    pub fn release_disk(&self, bytes: usize) {
+        let mut state = self.inner.lock().unwrap();
+
+        state.open_cpks -= 1;
+        state.disk_used -= bytes;
+    }
+
+    /// Closes a CPK decrypted to the temp folder whose copy stays there,
+    /// which keeps its disk space until [`release_kept_disk`](Self::release_kept_disk)
+    pub fn keep_disk(&self) {
+        self.inner.lock().unwrap().open_cpks -= 1;
+    }
+
+    /// Frees the disk space of a copy kept by [`keep_disk`](Self::keep_disk) once it is removed
+    pub fn release_kept_disk(&self, bytes: usize) {
+        self.inner.lock().unwrap().disk_used -= bytes;
+    }
+
+    /// Bytes of the disk budget in use
+    pub fn disk_used(&self) -> usize {
+        self.inner.lock().unwrap().disk_used
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn disk_limit(&self) -> usize {
+        self.disk_limit
+    }
+
+    pub fn max_open_cpks(&self) -> usize {
+        self.max_open_cpks
+    }
+
+    /// Number of decrypted CPKs alive, or being decrypted
+    pub fn open_cpks(&self) -> usize {
+        self.inner.lock().unwrap().open_cpks
+    }
+
+    /// How often the pool turned a file or a CPK down so far
+    pub fn stats(&self) -> PoolStats {
+        self.inner.lock().unwrap().stats
+    }
+}
\ No newline at end of file
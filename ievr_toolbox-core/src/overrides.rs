@@ -0,0 +1,262 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use crate::{CpkFile, CpkIndex, GameIndex, GameIndexMatch, IndexedCpk, normalize_path, relative_cpk_path};
+
+/// Decides which copy of a file is the real one when the same path is in several CPKs,
+/// such as a base archive and the archive of an update
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Folder holding the CPKs, which the winning CPKs are relative to
+    data_folder: PathBuf,
+    /// The CPK winning for each path found in several CPKs, by its path relative to the data folder,
+    /// since CPKs in different folders may have the same name
+    winners: HashMap<String, String>,
+    /// How many copies are overridden
+    overridden: usize,
+}
+
+impl Overrides {
+    /// Picks the winning CPK of every duplicated path. The CPK that `cpk_list.cfg.bin`
+    /// lists for the file wins, as this is the one the game loads. Otherwise the first CPK
+    /// of `priority` wins, and as a last resort the last CPK by path, so that archives
+    /// named after the one they patch (`data.cpk`, `data_patch.cpk`) take precedence.
+    /// The CPKs are told apart by their path relative to `data_folder`, and `cpk_list.cfg.bin`
+    /// and `priority` may give either this path or the name of the CPK.
+    pub fn new(game_index: &GameIndex, data_folder: &Path, cpk_index: Option<&CpkIndex>, priority: &[String]) -> Overrides {
+        let mut candidates: HashMap<String, Vec<&IndexedCpk>> = HashMap::new();
+        for found in game_index.files() {
+            candidates.entry(found.file.path()).or_default().push(found.cpk);
+        }
+
+        let mut overrides = Overrides { data_folder: data_folder.to_path_buf(), ..Default::default() };
+        for (path, mut cpks) in candidates {
+            cpks.sort_by(|a, b| a.path.cmp(&b.path));
+            cpks.dedup_by(|a, b| a.path == b.path);
+            if cpks.len() < 2 {
+                continue;
+            }
+
+            let cpk_paths: Vec<String> = cpks.iter().map(|cpk| cpk.relative_path.clone()).collect();
+
+            let winner = cpk_index.and_then(|cpk_index| listed_cpk(cpk_index, &path, &cpk_paths))
+                .or_else(|| priority.iter().find_map(|wanted| {
+                    let wanted = normalize_path(wanted);
+                    cpk_paths.iter().find(|cpk_path| {
+                        cpk_path.eq_ignore_ascii_case(&wanted) || cpk_name(cpk_path).eq_ignore_ascii_case(&wanted)
+                    })
+                }))
+                .unwrap_or_else(|| cpk_paths.last().unwrap())
+                .clone();
+
+            overrides.overridden += cpk_paths.len() - 1;
+            overrides.winners.insert(path, winner);
+        }
+
+        overrides
+    }
+
+    /// Path relative to the data folder of the CPK whose copy of the file is used, if the file is in several CPKs
+    pub fn winner(&self, path: &str) -> Option<&str> {
+        self.winners.get(path).map(String::as_str)
+    }
+
+    /// The copy of the file at `path` loaded by the game: the one of the winning CPK
+    /// if the file is in several CPKs
+    pub fn resolve<'a>(&self, game_index: &'a GameIndex, path: &str) -> Option<GameIndexMatch<'a>> {
+        let found = game_index.get(path)?;
+        let path = found.file.path();
+        let Some(winner) = self.winner(&path) else {
+            return Some(found);
+        };
+
+        game_index.find_by_name(&found.file.file_name)
+            .find(|copy| copy.cpk.relative_path == winner && copy.file.path() == path)
+            .or(Some(found))
+    }
+
+    /// Whether this copy of the file is hidden by a copy from another CPK
+    pub fn is_overridden(&self, file: &CpkFile) -> bool {
+        if self.winners.is_empty() {
+            return false;
+        }
+
+        let directory = normalize_path(file.directory.as_deref().unwrap_or_default());
+        let path = if directory.is_empty() {
+            file.file_name.clone()
+        } else {
+            format!("{directory}/{}", file.file_name)
+        };
+
+        match (self.winner(&path), file.cpk_path.as_deref()) {
+            (Some(winner), Some(cpk_path)) => winner != relative_cpk_path(&self.data_folder, cpk_path),
+            _ => false,
+        }
+    }
+
+    /// Number of paths found in several CPKs
+    pub fn len(&self) -> usize {
+        self.winners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.winners.is_empty()
+    }
+
+    /// Number of copies that lose to another CPK
+    pub fn overridden_count(&self) -> usize {
+        self.overridden
+    }
+}
+
+/// The only candidate CPK listed for the file in `cpk_list.cfg.bin`, if any.
+/// The candidates are paths relative to the data folder, which the list may only give the name of.
+fn listed_cpk<'a>(cpk_index: &CpkIndex, path: &str, candidates: &'a [String]) -> Option<&'a String> {
+    let (directory, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    let mut listed = candidates.iter().filter(|candidate| {
+        cpk_index.find(file_name).any(|entry| {
+            let listed_cpk = normalize_path(&entry.cpk_name);
+            (listed_cpk == **candidate || listed_cpk == cpk_name(candidate))
+                && entry.directory.as_deref().is_none_or(|entry_directory| entry_directory == directory)
+        })
+    });
+
+    match (listed.next(), listed.next()) {
+        (Some(winner), None) => Some(winner),
+        _ => None,
+    }
+}
+
+/// Name of the CPK at a path relative to the data folder
+fn cpk_name(cpk_path: &str) -> &str {
+    cpk_path.rsplit_once('/').map_or(cpk_path, |(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CpkIndexEntry, IndexedFile};
+
+    use super::*;
+
+    const DATA_FOLDER: &str = "game/data";
+
+    fn indexed_cpk(relative_path: &str, paths: &[&str]) -> IndexedCpk {
+        IndexedCpk {
+            path: Path::new(DATA_FOLDER).join(relative_path),
+            relative_path: relative_path.to_string(),
+            size: 0,
+            mtime: 0,
+            files: paths.iter().map(|path| {
+                let (directory, file_name) = path.rsplit_once('/').unwrap();
+                IndexedFile {
+                    directory: directory.to_string(),
+                    file_name: file_name.to_string(),
+                    file_offset: 0,
+                    file_size: 0,
+                    extract_size: 0,
+                    user_string: None,
+                }
+            }).collect(),
+        }
+    }
+
+    fn cpk_list(entries: &[(&str, &str, &str)]) -> CpkIndex {
+        let entries = entries.iter()
+            .map(|&(directory, file_name, cpk_name)| CpkIndexEntry {
+                directory: Some(directory.to_string()),
+                file_name: file_name.to_string(),
+                cpk_name: cpk_name.to_string(),
+            })
+            .collect();
+        CpkIndex::new(entries, 0, 0)
+    }
+
+    fn pick_winners(game_index: &GameIndex, cpk_index: Option<&CpkIndex>, priority: &[&str]) -> Overrides {
+        let priority: Vec<String> = priority.iter().map(|cpk| cpk.to_string()).collect();
+        Overrides::new(game_index, Path::new(DATA_FOLDER), cpk_index, &priority)
+    }
+
+    /// A base archive and its patch both holding `data/common/a.bin`
+    fn patched_game() -> GameIndex {
+        GameIndex::new(vec![
+            indexed_cpk("data.cpk", &["data/common/a.bin", "data/common/b.bin"]),
+            indexed_cpk("data_patch.cpk", &["data/common/a.bin"]),
+        ])
+    }
+
+    #[test]
+    fn last_cpk_by_path_wins_by_default() {
+        let game_index = patched_game();
+        let overrides = pick_winners(&game_index, None, &[]);
+
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(overrides.overridden_count(), 1);
+        assert_eq!(overrides.winner("data/common/a.bin"), Some("data_patch.cpk"));
+        assert_eq!(overrides.winner("data/common/b.bin"), None);
+    }
+
+    #[test]
+    fn priority_beats_the_last_cpk_by_path() {
+        let game_index = patched_game();
+
+        // The names are compared without case, and CPKs missing from the game are skipped
+        let overrides = pick_winners(&game_index, None, &["missing.cpk", "DATA.CPK", "data_patch.cpk"]);
+        assert_eq!(overrides.winner("data/common/a.bin"), Some("data.cpk"));
+    }
+
+    #[test]
+    fn cpk_list_beats_priority() {
+        let game_index = patched_game();
+
+        let cpk_index = cpk_list(&[("data/common", "a.bin", "data.cpk")]);
+        let overrides = pick_winners(&game_index, Some(&cpk_index), &["data_patch.cpk"]);
+        assert_eq!(overrides.winner("data/common/a.bin"), Some("data.cpk"));
+
+        // An entry for another directory doesn't tell which copy is loaded
+        let cpk_index = cpk_list(&[("data/other", "a.bin", "data.cpk")]);
+        let overrides = pick_winners(&game_index, Some(&cpk_index), &["data_patch.cpk"]);
+        assert_eq!(overrides.winner("data/common/a.bin"), Some("data_patch.cpk"));
+
+        // Neither does a list naming both CPKs, which leaves it to the priority
+        let cpk_index = cpk_list(&[("data/common", "a.bin", "data.cpk"), ("data/common", "a.bin", "data_patch.cpk")]);
+        let overrides = pick_winners(&game_index, Some(&cpk_index), &["data.cpk"]);
+        assert_eq!(overrides.winner("data/common/a.bin"), Some("data.cpk"));
+    }
+
+    #[test]
+    fn same_named_cpks_in_different_folders() {
+        let game_index = GameIndex::new(vec![
+            indexed_cpk("data.cpk", &["data/common/a.bin"]),
+            indexed_cpk("dlc/data.cpk", &["data/common/a.bin"]),
+        ]);
+
+        let overrides_by_path = pick_winners(&game_index, None, &[]);
+        assert_eq!(overrides_by_path.winner("data/common/a.bin"), Some("dlc/data.cpk"));
+
+        // The path relative to the data folder picks one of them, while the name picks the first by path
+        let overrides_by_priority = pick_winners(&game_index, None, &["dlc\\data.cpk"]);
+        assert_eq!(overrides_by_priority.winner("data/common/a.bin"), Some("dlc/data.cpk"));
+        let overrides_by_name = pick_winners(&game_index, None, &["data.cpk"]);
+        assert_eq!(overrides_by_name.winner("data/common/a.bin"), Some("data.cpk"));
+
+        // A CPK list only giving the name matches both, so it can't tell
+        let cpk_index = cpk_list(&[("data/common", "a.bin", "data.cpk")]);
+        let overrides_by_list = pick_winners(&game_index, Some(&cpk_index), &[]);
+        assert_eq!(overrides_by_list.winner("data/common/a.bin"), Some("dlc/data.cpk"));
+        let cpk_index = cpk_list(&[("data/common", "a.bin", "/data.cpk")]);
+        let overrides_by_list = pick_winners(&game_index, Some(&cpk_index), &["dlc/data.cpk"]);
+        assert_eq!(overrides_by_list.winner("data/common/a.bin"), Some("dlc/data.cpk"));
+
+        // The copy of the winning CPK is resolved, and the other one is overridden
+        let resolved = overrides_by_name.resolve(&game_index, "data/common/a.bin").unwrap();
+        assert_eq!(resolved.cpk.relative_path, "data.cpk");
+        let copies: Vec<CpkFile> = game_index.files().map(|found| found.cpk_file()).collect();
+        assert!(!overrides_by_name.is_overridden(&copies[0]));
+        assert!(overrides_by_name.is_overridden(&copies[1]));
+
+        let resolved = overrides_by_path.resolve(&game_index, "data/common/a.bin").unwrap();
+        assert_eq!(resolved.cpk.relative_path, "dlc/data.cpk");
+        assert!(overrides_by_path.is_overridden(&copies[0]));
+        assert!(!overrides_by_path.is_overridden(&copies[1]));
+    }
+}
@@ -0,0 +1,609 @@
+use std::{
+    collections::VecDeque, fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread, time::Instant
+};
+
+use crossbeam::deque::Worker;
+
+use crate::{
+    CancellationToken, CpkData, CpkFile, DecryptCache, Decompressor, DecryptedCpk, DumpEvent, DumpStats, IoThrottle, MemoryPool, ProgressSink,
+    QueueMetrics, SilentProgress, TocParser, decompress_files, extract_cpk_files, relative_cpk_path, try_decrypt_cpk, work_queue::WorkQueue,
+};
+
+/// Where the files extracted by a [`DumpPipeline`] are written
+pub trait OutputSink: Send + Sync {
+    /// Called once the files of a CPK are selected, before any of them is written
+    fn begin_cpk(&self, _cpk_name: &Arc<str>, _file_count: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes the file, returning the CRC32 of its extracted content if the decompressor computes checksums
+    fn write_file(&self, decompressor: &mut Decompressor, file: &CpkFile) -> io::Result<Option<u32>>;
+
+    /// Called once no file is left to write, even when the dump stopped early,
+    /// so that the files already written stay readable
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the files in a folder, at `DirName/FileName`
+#[derive(Debug, Clone)]
+pub struct FolderSink {
+    folder: PathBuf,
+}
+
+impl FolderSink {
+    pub fn new(folder: impl Into<PathBuf>) -> FolderSink {
+        FolderSink { folder: folder.into() }
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+}
+
+impl OutputSink for FolderSink {
+    fn write_file(&self, decompressor: &mut Decompressor, file: &CpkFile) -> io::Result<Option<u32>> {
+        decompress_files(decompressor, file, &self.folder)
+    }
+}
+
+type FileFilter = dyn Fn(&CpkFile) -> bool + Send + Sync;
+
+/// Extracts the files of several CPKs in parallel, on worker threads that share all the work.
+/// While few files are waiting and the budget allows it, a worker decrypts the next CPK, in RAM or
+/// to the temp folder depending on its size, and queues its files from the biggest to the smallest.
+/// Otherwise it writes the waiting files to the output sink: first the files of the CPKs it decrypted,
+/// then the ones it takes from the other workers.
+///
+/// Each decrypted CPK stays mapped until its last file is written, so the number of them alive at
+/// once is capped along with the memory and disk budgets. Once it is reached, the workers write
+/// the waiting files until a CPK is released, which also bounds the number of files waiting.
+///
+/// ```ignore
+/// DumpPipeline::new(cpk_paths, Arc::new(FolderSink::new("extracted")))
+///     .threads(8)
+///     .memory_limit(8 * 1024 * 1024 * 1024)
+///     .filter(|file| file.file_name.ends_with(".cfg.bin"))
+///     .progress(Arc::new(StderrProgress))
+///     .run()?;
+/// ```
+pub struct DumpPipeline {
+    cpks: Vec<PathBuf>,
+    sink: Arc<dyn OutputSink>,
+    data_folder: Option<PathBuf>,
+    temp_folder: PathBuf,
+    threads: usize,
+    memory_limit: usize,
+    disk_limit: usize,
+    size_threshold: Option<usize>,
+    max_open_cpks: Option<usize>,
+    cache_size: usize,
+    checksums: bool,
+    cancellation: CancellationToken,
+    throttle: IoThrottle,
+    filter: Option<Box<FileFilter>>,
+    progress: Arc<dyn ProgressSink>,
+    metrics: QueueMetrics,
+    stats: DumpStats,
+}
+
+impl DumpPipeline {
+    /// Prepares the extraction of the CPKs at `cpks` to `sink`, processed in this order.
+    /// By default, a single thread does everything, and there is no memory or disk limit.
+    pub fn new(cpks: Vec<PathBuf>, sink: Arc<dyn OutputSink>) -> DumpPipeline {
+        DumpPipeline {
+            cpks,
+            sink,
+            data_folder: None,
+            temp_folder: PathBuf::from("temp"),
+            threads: 1,
+            memory_limit: usize::MAX,
+            disk_limit: usize::MAX,
+            size_threshold: None,
+            max_open_cpks: None,
+            cache_size: 0,
+            checksums: false,
+            cancellation: CancellationToken::new(),
+            throttle: IoThrottle::unlimited(),
+            filter: None,
+            progress: Arc::new(SilentProgress),
+            metrics: QueueMetrics::default(),
+            stats: DumpStats::default(),
+        }
+    }
+
+    /// Number of worker threads, each of them decrypting or decompressing depending on what is waiting
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Bytes of RAM the CPKs decrypted in RAM and the files being decompressed may use at once
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Bytes of disk space the CPKs decrypted to the temp folder may use at once
+    pub fn disk_limit(mut self, bytes: usize) -> Self {
+        self.disk_limit = bytes;
+        self
+    }
+
+    /// CPKs of this size or more are decrypted to the temp folder instead of RAM.
+    /// Defaults to half the memory limit shared by half the threads, the usual number of
+    /// CPKs decrypted at once, so that a CPK and the files it contains fit together in the budget.
+    /// A threshold above the memory limit is lowered to it.
+    pub fn size_threshold(mut self, bytes: usize) -> Self {
+        self.size_threshold = Some(bytes);
+        self
+    }
+
+    /// Number of decrypted CPKs alive at once, including the ones being decrypted. Defaults to twice
+    /// the number of threads, enough for every thread to decrypt while the files of as many CPKs wait.
+    pub fn max_open_cpks(mut self, cpks: usize) -> Self {
+        self.max_open_cpks = Some(cpks.max(1));
+        self
+    }
+
+    /// Folder holding the CPKs, which names them after their path relative to it, as CPKs in
+    /// different folders may have the same name. Without it, the CPKs are named after their file.
+    pub fn data_folder(mut self, data_folder: impl Into<PathBuf>) -> Self {
+        self.data_folder = Some(data_folder.into());
+        self
+    }
+
+    /// Folder where the big CPKs are decrypted, which is a [`DecryptCache`]
+    pub fn temp_folder(mut self, temp_folder: impl Into<PathBuf>) -> Self {
+        self.temp_folder = temp_folder.into();
+        self
+    }
+
+    /// Keeps the CPKs decrypted to the temp folder once their files are written, as long as the copies
+    /// kept take at most `bytes`, instead of removing them right away. The copies kept count against the
+    /// disk limit, and the ones released the longest time ago are removed first when the cache or
+    /// the disk limit is full.
+    pub fn cache_size(mut self, bytes: usize) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Computes the CRC32 of every extracted file, passed to the output sink
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Stops the dump once `cancellation` is cancelled. The CPK being decrypted is dropped, the files
+    /// being written are finished, and every other decrypted CPK is released without extracting anything.
+    pub fn cancellation(mut self, cancellation: &CancellationToken) -> Self {
+        self.cancellation = cancellation.clone();
+        self
+    }
+
+    /// Limits the bytes read and written per second by the decryption and the extraction together.
+    /// A value of 0 doesn't limit them, which is the default.
+    pub fn max_io(mut self, bytes_per_second: usize) -> Self {
+        self.throttle = IoThrottle::new(bytes_per_second);
+        self
+    }
+
+    /// Only extracts the files accepted by `filter`
+    pub fn filter(mut self, filter: impl Fn(&CpkFile) -> bool + Send + Sync + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Keeps the depths of the queues of the dump up to date in `metrics`, to read while it runs or after it
+    pub fn metrics(mut self, metrics: &QueueMetrics) -> Self {
+        self.metrics = metrics.clone();
+        self
+    }
+
+    /// Collects the timings of every CPK and how often the budgets held the dump back in `stats`,
+    /// to read once the dump is done
+    pub fn stats(mut self, stats: &DumpStats) -> Self {
+        self.stats = stats.clone();
+        self
+    }
+
+    /// Sends the events of the dump to `progress`, from the pipeline threads. Nothing is reported by default.
+    pub fn progress(mut self, progress: Arc<dyn ProgressSink>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// The threshold between CPKs decrypted in RAM and to the temp folder. It never goes above
+    /// the memory limit, as a CPK bigger than the whole budget would wait for its memory forever.
+    pub fn in_ram_threshold(&self) -> usize {
+        self.size_threshold
+            .unwrap_or(self.memory_limit / self.threads.div_ceil(2) / 2)
+            .min(self.memory_limit)
+    }
+
+    /// The cap on decrypted CPKs alive at once
+    pub fn open_cpks_limit(&self) -> usize {
+        self.max_open_cpks.unwrap_or(self.threads * 2)
+    }
+
+    /// Runs the dump until every file is written. The first error stops the dump like a cancellation
+    /// and is returned, while a cancelled dump returns an [`io::ErrorKind::Interrupted`] error.
+    pub fn run(self) -> io::Result<()> {
+        let (files, workers) = WorkQueue::new(self.threads);
+        let state = DumpState {
+            memory_pool: MemoryPool::new(self.memory_limit, self.disk_limit).with_max_open_cpks(self.open_cpks_limit()),
+            files,
+            next_cpk: Mutex::new(0),
+            decrypting: AtomicUsize::new(0),
+            decryption_done: AtomicBool::new(false),
+            kept: Mutex::new(VecDeque::new()),
+            failure: Failure::default(),
+        };
+
+        let (pipeline, state_ref) = (&self, &state);
+        thread::scope(|scope| {
+            for local in workers {
+                scope.spawn(move || pipeline.worker(local, state_ref));
+            }
+        });
+
+        // When the dump stopped early, some CPKs were never decrypted
+        self.decryption_done(&state);
+        if let Err(e) = self.sink.finish() {
+            self.fail(&state.failure, e);
+        }
+        self.stats.set_memory(state.memory_pool.stats());
+
+        match state.failure.take() {
+            Some(e) => Err(e),
+            None => self.cancellation.check(),
+        }
+    }
+
+    fn event(&self, event: DumpEvent) {
+        self.progress.event(event);
+    }
+
+    /// Whether the dump was cancelled or failed, in which case nothing more is decrypted or written
+    fn should_stop(&self, failure: &Failure) -> bool {
+        self.cancellation.is_cancelled() || failure.is_set()
+    }
+
+    /// Reports the error, and keeps it if it is the first one
+    fn fail(&self, failure: &Failure, e: io::Error) {
+        self.event(DumpEvent::Error(&e));
+        failure.set(e);
+    }
+
+    fn worker(&self, local: Worker<CpkFile>, state: &DumpState) {
+        let mut toc_parser = TocParser::default();
+        let mut decompressor = if self.checksums {
+            Decompressor::with_checksum()
+        } else {
+            Decompressor::default()
+        };
+        decompressor.set_throttle(&self.throttle);
+
+        loop {
+            let generation = state.files.generation();
+
+            // Another CPK is only decrypted while few files are waiting,
+            // otherwise the threads and the memory are better spent writing them
+            if state.files.len() < self.threads && let Some((cpk_path, cpk_size)) = self.next_cpk(state) {
+                self.decrypt(cpk_path, cpk_size, &local, &mut toc_parser, state);
+                continue;
+            }
+
+            if let Some(extracted_file) = state.files.pop(&local) {
+                self.metrics.set_files_waiting(state.files.len());
+                self.write(&mut decompressor, extracted_file, state);
+                continue;
+            }
+
+            if self.all_cpks_taken(state) && state.decrypting.load(Ordering::SeqCst) == 0 && state.files.len() == 0 {
+                // Nothing else will be queued, and the other workers may be waiting for it
+                state.files.notify();
+                return;
+            }
+
+            state.files.wait(generation);
+        }
+    }
+
+    /// Takes the next CPK to decrypt along with its memory or disk budget. Returns `None` if the
+    /// dump is stopping, if every CPK was taken, or if there isn't enough budget left for now.
+    fn next_cpk(&self, state: &DumpState) -> Option<(&Path, usize)> {
+        if self.should_stop(&state.failure) {
+            return None;
+        }
+
+        let mut next_cpk = state.next_cpk.lock().unwrap();
+        let cpk_path = self.cpks.get(*next_cpk)?;
+
+        let cpk_size = match fs::metadata(cpk_path) {
+            Ok(metadata) => metadata.len() as usize,
+            Err(e) => {
+                self.fail(&state.failure, e);
+                return None;
+            }
+        };
+
+        let acquired = if cpk_size < self.in_ram_threshold() {
+            // This will map the file to RAM instead of a file
+            state.memory_pool.try_acquire_decryption(cpk_size)
+        } else {
+            if cpk_size > state.memory_pool.disk_limit() {
+                self.fail(&state.failure, io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!("Insufficient disk space to decrypt {}", cpk_path.display()),
+                ));
+                return None;
+            }
+            // The copies kept for the cache make room for the CPKs still to decrypt
+            self.evict_kept(state, cpk_size);
+            state.memory_pool.try_acquire_disk(cpk_size)
+        };
+
+        if !acquired {
+            return None;
+        }
+
+        *next_cpk += 1;
+        let decrypting = state.decrypting.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metrics.set_cpks_decrypting(decrypting);
+        self.metrics.set_cpks_open(state.memory_pool.open_cpks());
+        Some((cpk_path, cpk_size))
+    }
+
+    /// Whether there is no CPK left to decrypt
+    fn all_cpks_taken(&self, state: &DumpState) -> bool {
+        self.should_stop(&state.failure) || *state.next_cpk.lock().unwrap() == self.cpks.len()
+    }
+
+    /// Decrypts the CPK taken by [`next_cpk`](Self::next_cpk) and queues its files in `local`
+    fn decrypt(&self, cpk_path: &Path, cpk_size: usize, local: &Worker<CpkFile>, toc_parser: &mut TocParser, state: &DumpState) {
+        let size_threshold = self.in_ram_threshold();
+        let decrypt_start = Instant::now();
+        match try_decrypt_cpk(cpk_path, &self.temp_folder, size_threshold, &self.cancellation, &self.throttle) {
+            Ok((decrypted_cpk, decryption)) => {
+                self.event(DumpEvent::CpkDecrypted { path: cpk_path, size: cpk_size as u64 });
+
+                let cpk_name = match &self.data_folder {
+                    Some(data_folder) => Arc::<str>::from(relative_cpk_path(data_folder, cpk_path)),
+                    None => Arc::<str>::from(cpk_path.file_name().unwrap_or_default().to_string_lossy()),
+                };
+                self.stats.record_decryption(&cpk_name, cpk_size as u64, decryption, decrypt_start.elapsed());
+                let mut files = self.select_files(cpk_path, cpk_name, decrypted_cpk, toc_parser, state);
+
+                // The biggest files are written first, so that the smallest ones even out the end of the dump
+                files.sort_unstable_by(|a, b| b.cmp(a));
+                for file in &files {
+                    self.event(DumpEvent::FileQueued(file));
+                }
+                state.files.push(local, files);
+                self.metrics.set_files_waiting(state.files.len());
+            }
+            Err(e) => {
+                if cpk_size < size_threshold {
+                    state.memory_pool.release_decryption(cpk_size);
+                } else {
+                    state.memory_pool.release_disk(cpk_size);
+                }
+                self.metrics.set_cpks_open(state.memory_pool.open_cpks());
+
+                if !self.cancellation.is_cancelled() {
+                    self.fail(&state.failure, e);
+                }
+            }
+        }
+
+        // Only counted as done once its files are queued, so that no worker
+        // sees nothing queued and nothing being decrypted while files are coming
+        let decrypting = state.decrypting.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.metrics.set_cpks_decrypting(decrypting);
+        if self.all_cpks_taken(state) && state.decrypting.load(Ordering::SeqCst) == 0 {
+            self.decryption_done(state);
+        }
+        state.files.notify();
+    }
+
+    /// Reports that no CPK is left to decrypt, once
+    fn decryption_done(&self, state: &DumpState) {
+        if !state.decryption_done.swap(true, Ordering::SeqCst) {
+            self.event(DumpEvent::DecryptionDone);
+        }
+    }
+
+    /// Lists the files of a decrypted CPK accepted by the filter, and announces them to the sink.
+    /// CPKs with nothing to extract are released right away.
+    fn select_files(&self, cpk_path: &Path, cpk_name: Arc<str>, decrypted_cpk: DecryptedCpk, toc_parser: &mut TocParser, state: &DumpState) -> Vec<CpkFile> {
+        if self.should_stop(&state.failure) {
+            if let Some(cpk_data) = Arc::into_inner(decrypted_cpk) {
+                self.release_data(Some(cpk_path), cpk_data, state);
+            }
+            return Vec::new();
+        }
+
+        let cpk_path = Arc::<Path>::from(cpk_path);
+        let mut files = match extract_cpk_files(decrypted_cpk.clone(), toc_parser, self.progress.as_ref()) {
+            Ok(files) => files,
+            Err(e) => {
+                // Nothing is announced to the sink, so that the CPK isn't taken as done
+                self.fail(&state.failure, io::Error::new(e.kind(), format!("{}: {e}", cpk_path.display())));
+                if let Some(cpk_data) = Arc::into_inner(decrypted_cpk) {
+                    self.release_data(Some(&cpk_path), cpk_data, state);
+                }
+                return Vec::new();
+            }
+        };
+        for file in &mut files {
+            file.cpk_name = Some(cpk_name.clone());
+            file.cpk_path = Some(cpk_path.clone());
+        }
+
+        if let Some(filter) = &self.filter {
+            files.retain(|file| filter(file));
+        }
+
+        if let Err(e) = self.sink.begin_cpk(&cpk_name, files.len()) {
+            self.fail(&state.failure, e);
+        }
+
+        // No file holds the CPK anymore once they were all filtered out
+        if let Some(cpk_data) = Arc::into_inner(decrypted_cpk) {
+            self.release_data(Some(&cpk_path), cpk_data, state);
+        }
+        files
+    }
+
+    fn write(&self, decompressor: &mut Decompressor, extracted_file: CpkFile, state: &DumpState) {
+        // Once stopped, the remaining files are only taken to release their CPK
+        if !self.should_stop(&state.failure) {
+            match self.write_file(decompressor, &extracted_file, &state.memory_pool) {
+                Ok(()) => self.event(DumpEvent::FileExtracted(&extracted_file)),
+                Err(e) => self.fail(&state.failure, e),
+            }
+        }
+
+        self.release_cpk(extracted_file, state);
+
+        // The memory freed may be enough for another worker to decrypt the next CPK
+        state.files.notify();
+    }
+
+    fn write_file(&self, decompressor: &mut Decompressor, extracted_file: &CpkFile, memory_pool: &MemoryPool) -> io::Result<()> {
+        // Files bigger than the whole memory budget are streamed through a small buffer instead.
+        // Like the rest of the decompressor, this buffer isn't counted in the budget: waiting for
+        // it could block forever when the budget is taken by the CPK the file comes from.
+        let extract_size = extracted_file.extract_size as usize;
+        if extract_size > memory_pool.limit() {
+            return self.low_memory_write(decompressor, extracted_file);
+        }
+
+        // For the same reason, files whose memory is taken for now are streamed too
+        if !memory_pool.try_acquire_decompression(extract_size) {
+            let result = self.low_memory_write(decompressor, extracted_file);
+            memory_pool.end_decompression_without_memory();
+            return result;
+        }
+
+        let result = self.timed_write(decompressor, extracted_file);
+        memory_pool.release(extract_size);
+
+        result
+    }
+
+    fn low_memory_write(&self, decompressor: &mut Decompressor, extracted_file: &CpkFile) -> io::Result<()> {
+        decompressor.set_low_memory(true);
+        let result = self.timed_write(decompressor, extracted_file);
+        decompressor.set_low_memory(false);
+        result
+    }
+
+    /// Writes the file to the sink, and records the time it took
+    fn timed_write(&self, decompressor: &mut Decompressor, extracted_file: &CpkFile) -> io::Result<()> {
+        let write_start = Instant::now();
+        self.sink.write_file(decompressor, extracted_file)?;
+        self.stats.record_file(extracted_file, write_start.elapsed());
+        Ok(())
+    }
+
+    /// Frees the CPK of the file once its last file is written
+    fn release_cpk(&self, extracted_file: CpkFile, state: &DumpState) {
+        let cpk_path = extracted_file.cpk_path.clone();
+
+        if let Some(cpk_data) = extracted_file.into_last_data() {
+            self.release_data(cpk_path.as_deref(), cpk_data, state);
+        }
+    }
+
+    /// Frees a decrypted CPK: its RAM budget if it was decrypted in RAM, or its temp file
+    /// and disk budget otherwise, unless the copy is kept for the cache
+    fn release_data(&self, cpk_path: Option<&Path>, cpk_data: CpkData, state: &DumpState) {
+        let memory_pool = &state.memory_pool;
+        let cpk_size = cpk_data.len();
+        match cpk_data {
+            CpkData::Small(_) => memory_pool.release_decryption(cpk_size),
+            CpkData::Big(mmap) => {
+                // The file must be unmapped before it can be removed on Windows
+                drop(mmap);
+                match cpk_path {
+                    Some(cpk_path) if self.cache_size > 0 => {
+                        memory_pool.keep_disk();
+                        state.kept.lock().unwrap().push_back((cpk_path.to_path_buf(), cpk_size));
+                        self.evict_kept(state, 0);
+                    }
+                    _ => {
+                        if let Some(cpk_path) = cpk_path {
+                            let _ = DecryptCache::new(&self.temp_folder).remove(cpk_path);
+                        }
+                        memory_pool.release_disk(cpk_size);
+                    }
+                }
+            }
+        }
+        self.metrics.set_cpks_open(memory_pool.open_cpks());
+    }
+
+    /// Removes the copies kept for the cache, the ones released the longest time ago first,
+    /// until they fit in the cache size and `needed` more bytes fit in the disk budget
+    fn evict_kept(&self, state: &DumpState, needed: usize) {
+        let memory_pool = &state.memory_pool;
+        let mut kept = state.kept.lock().unwrap();
+        loop {
+            let kept_size: usize = kept.iter().map(|(_, size)| size).sum();
+            let disk_full = memory_pool.disk_used().saturating_add(needed) > memory_pool.disk_limit();
+            if kept_size <= self.cache_size && !disk_full {
+                return;
+            }
+
+            let Some((cpk_path, size)) = kept.pop_front() else {
+                return;
+            };
+            let _ = DecryptCache::new(&self.temp_folder).remove(&cpk_path);
+            memory_pool.release_kept_disk(size);
+        }
+    }
+}
+
+/// What the workers of a running dump share
+struct DumpState {
+    memory_pool: MemoryPool,
+    /// Files waiting to be written
+    files: WorkQueue<CpkFile>,
+    /// Index of the next CPK to decrypt
+    next_cpk: Mutex<usize>,
+    /// Number of CPKs being decrypted
+    decrypting: AtomicUsize,
+    decryption_done: AtomicBool,
+    /// Copies of CPKs decrypted to the temp folder kept for the cache, along with their size,
+    /// the ones released the longest time ago first
+    kept: Mutex<VecDeque<(PathBuf, usize)>>,
+    failure: Failure,
+}
+
+/// The first error met by the pipeline threads
+#[derive(Default)]
+struct Failure {
+    error: Mutex<Option<io::Error>>,
+    is_set: AtomicBool,
+}
+
+impl Failure {
+    fn set(&self, e: io::Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            *error = Some(e);
+            self.is_set.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.is_set.load(Ordering::Relaxed)
+    }
+
+    fn take(&self) -> Option<io::Error> {
+        self.error.lock().unwrap().take()
+    }
+}
@@ -0,0 +1,56 @@
+use std::{io, path::Path};
+
+use crate::CpkFile;
+
+/// Something that happened while extracting CPKs, reported to a [`ProgressSink`]
+#[derive(Debug, Clone, Copy)]
+pub enum DumpEvent<'a> {
+    /// The CPK at this path was decrypted, in RAM or to the temp folder
+    CpkDecrypted { path: &'a Path, size: u64 },
+    /// Every CPK was decrypted
+    DecryptionDone,
+    /// The file was selected and is waiting to be extracted
+    FileQueued(&'a CpkFile),
+    /// The file was written to the output
+    FileExtracted(&'a CpkFile),
+    /// Something looks wrong, but the extraction goes on
+    Warning(&'a str),
+    /// An error that stops the extraction
+    Error(&'a io::Error),
+    /// Bytes of the file decrypted or encrypted so far by [`decrypt`](crate::decrypt) or [`encrypt`](crate::encrypt)
+    CryptProgress { done: u64, total: u64 },
+}
+
+/// Receives the events of the core functions instead of them printing anything.
+/// Events can be sent from several threads at once.
+pub trait ProgressSink: Send + Sync {
+    fn event(&self, event: DumpEvent);
+}
+
+impl<F: Fn(DumpEvent) + Send + Sync> ProgressSink for F {
+    fn event(&self, event: DumpEvent) {
+        self(event)
+    }
+}
+
+/// Ignores every event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SilentProgress;
+
+impl ProgressSink for SilentProgress {
+    fn event(&self, _event: DumpEvent) {}
+}
+
+/// Prints the warnings and errors to stderr, and ignores the other events
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StderrProgress;
+
+impl ProgressSink for StderrProgress {
+    fn event(&self, event: DumpEvent) {
+        match event {
+            DumpEvent::Warning(message) => eprintln!("{message}"),
+            DumpEvent::Error(e) => eprintln!("{e}"),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+/// How much work waits between the stages of a running [`DumpPipeline`](crate::DumpPipeline).
+/// The handle can be cloned and read from another thread while the dump runs.
+#[derive(Debug, Clone, Default)]
+pub struct QueueMetrics {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    files_waiting: Depth,
+    cpks_decrypting: Depth,
+    cpks_open: Depth,
+}
+
+/// The queue depths at one point of the dump, and the highest they reached so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDepths {
+    /// Files selected and waiting to be written
+    pub files_waiting: usize,
+    /// CPKs being decrypted
+    pub cpks_decrypting: usize,
+    /// Decrypted CPKs alive, in RAM or in the temp folder, including the ones being decrypted
+    pub cpks_open: usize,
+    pub peak_files_waiting: usize,
+    pub peak_cpks_decrypting: usize,
+    pub peak_cpks_open: usize,
+}
+
+impl QueueMetrics {
+    pub fn depths(&self) -> QueueDepths {
+        let counters = &self.inner;
+        QueueDepths {
+            files_waiting: counters.files_waiting.current(),
+            cpks_decrypting: counters.cpks_decrypting.current(),
+            cpks_open: counters.cpks_open.current(),
+            peak_files_waiting: counters.files_waiting.peak(),
+            peak_cpks_decrypting: counters.cpks_decrypting.peak(),
+            peak_cpks_open: counters.cpks_open.peak(),
+        }
+    }
+
+    pub(crate) fn set_files_waiting(&self, files: usize) {
+        self.inner.files_waiting.set(files);
+    }
+
+    pub(crate) fn set_cpks_decrypting(&self, cpks: usize) {
+        self.inner.cpks_decrypting.set(cpks);
+    }
+
+    pub(crate) fn set_cpks_open(&self, cpks: usize) {
+        self.inner.cpks_open.set(cpks);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Depth {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Depth {
+    fn set(&self, depth: usize) {
+        self.current.store(depth, Ordering::Relaxed);
+        self.peak.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
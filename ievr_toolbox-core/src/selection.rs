@@ -0,0 +1,445 @@
+use std::{collections::{HashMap, HashSet}, fmt, fs, io, path::Path};
+
+use regex::Regex;
+
+use crate::{CpkFile, CpkIndex, GameIndex, normalize_path};
+
+#[derive(Debug, Clone, Copy)]
+enum SizeOp {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+}
+
+#[derive(Debug)]
+enum Term {
+    /// Matched against the file name only
+    Name(Regex),
+    /// Matched against the full path, `DirName/FileName`
+    Path(Regex),
+    Extension(Vec<String>),
+    /// Compared with the extracted size of the file
+    Size(SizeOp, u64),
+}
+
+impl Term {
+    fn parse(term: &str) -> Result<Term, String> {
+        if let Some(glob) = term.strip_prefix("glob:") {
+            return Self::pattern(&glob_to_regex(glob), glob.contains('/'));
+        }
+
+        if let Some(re) = term.strip_prefix("re:") {
+            return Self::pattern(re, re.contains('/'));
+        }
+
+        if let Some(extensions) = term.strip_prefix("ext:") {
+            let extensions = extensions.split(',')
+                .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                .filter(|ext| !ext.is_empty())
+                .collect::<Vec<_>>();
+
+            if extensions.is_empty() {
+                return Err("no extension given".to_string());
+            }
+            return Ok(Term::Extension(extensions));
+        }
+
+        if let Some(condition) = term.strip_prefix("size") {
+            let (op, value) = if let Some(value) = condition.strip_prefix("<=") {
+                (SizeOp::LessOrEqual, value)
+            } else if let Some(value) = condition.strip_prefix(">=") {
+                (SizeOp::GreaterOrEqual, value)
+            } else if let Some(value) = condition.strip_prefix('<') {
+                (SizeOp::Less, value)
+            } else if let Some(value) = condition.strip_prefix('>') {
+                (SizeOp::Greater, value)
+            } else if let Some(value) = condition.strip_prefix('=') {
+                (SizeOp::Equal, value)
+            } else {
+                // Not a size filter, just a regex starting with "size"
+                return Self::pattern(term, term.contains('/'));
+            };
+
+            return parse_size(value)
+                .map(|size| Term::Size(op, size))
+                .ok_or_else(|| format!("invalid size {value}"));
+        }
+
+        Self::pattern(term, term.contains('/'))
+    }
+
+    fn pattern(re: &str, full_path: bool) -> Result<Term, String> {
+        // Regex errors span several lines, the last one holds the actual reason
+        let re = Regex::new(re)
+            .map_err(|e| e.to_string().lines().last().unwrap_or_default().to_string())?;
+
+        Ok(if full_path { Term::Path(re) } else { Term::Name(re) })
+    }
+
+    fn matches(&self, path: &str, file_name: &str, size: u64) -> bool {
+        match self {
+            Term::Name(re) => re.is_match(file_name),
+            Term::Path(re) => re.is_match(path),
+            Term::Extension(extensions) => extensions.iter().any(|ext| has_extension(file_name, ext)),
+            Term::Size(op, limit) => match op {
+                SizeOp::Less => size < *limit,
+                SizeOp::LessOrEqual => size <= *limit,
+                SizeOp::Greater => size > *limit,
+                SizeOp::GreaterOrEqual => size >= *limit,
+                SizeOp::Equal => size == *limit,
+            },
+        }
+    }
+
+    /// Whether the term matches when only the file name is known,
+    /// `None` if the term depends on something else
+    fn matches_name(&self, file_name: &str) -> Option<bool> {
+        match self {
+            Term::Name(re) => Some(re.is_match(file_name)),
+            Term::Extension(extensions) => Some(extensions.iter().any(|ext| has_extension(file_name, ext))),
+            Term::Path(_) | Term::Size(..) => None,
+        }
+    }
+}
+
+/// A line of the rules file: every term must match
+#[derive(Debug)]
+struct Rule {
+    terms: Vec<Term>,
+}
+
+impl Rule {
+    fn matches(&self, path: &str, file_name: &str, size: u64) -> bool {
+        self.terms.iter().all(|term| term.matches(path, file_name, size))
+    }
+
+    fn may_match_name(&self, file_name: &str) -> bool {
+        self.terms.iter().all(|term| term.matches_name(file_name).unwrap_or(true))
+    }
+}
+
+/// A line of the rules file that couldn't be parsed
+#[derive(Debug, Clone)]
+pub struct InvalidRule {
+    pub line: usize,
+    pub rule: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: invalid rule {} ({})", self.line, self.rule, self.reason)
+    }
+}
+
+/// Rules selecting which files get extracted.
+///
+/// Each line holds one or more terms separated by spaces, which must all match:
+/// - a regex (optionally prefixed by `re:`) or a glob prefixed by `glob:`.
+///   Patterns containing a `/` are matched against the full path `DirName/FileName`,
+///   the others against the file name only
+/// - `ext:png,dds` to filter on the extension
+/// - `size<1M`, `size>=512K`... to filter on the extracted size
+///
+/// Lines starting with `!` exclude the files they match, lines starting with `#`
+/// and blank lines are ignored. A file is selected if it matches at least one
+/// rule (or if there are only exclusion rules) and no exclusion rule.
+#[derive(Debug, Default)]
+pub struct SelectionRules {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+}
+
+impl SelectionRules {
+    /// Parses the rules, returning the lines that were ignored because they are invalid
+    pub fn parse(text: &str) -> (SelectionRules, Vec<InvalidRule>) {
+        let mut rules = SelectionRules::default();
+        let mut invalid = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (exclude, rule) = match line.strip_prefix('!') {
+                Some(rule) => (true, rule.trim_start()),
+                None => (false, line),
+            };
+
+            let terms: Result<Vec<Term>, String> = rule.split_whitespace().map(Term::parse).collect();
+
+            match terms {
+                Ok(terms) if !terms.is_empty() => {
+                    if exclude {
+                        rules.exclude.push(Rule { terms });
+                    } else {
+                        rules.include.push(Rule { terms });
+                    }
+                }
+                Ok(_) => invalid.push(InvalidRule { line: i + 1, rule: line.to_string(), reason: "empty rule".to_string() }),
+                Err(reason) => invalid.push(InvalidRule { line: i + 1, rule: line.to_string(), reason }),
+            }
+        }
+
+        (rules, invalid)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<(SelectionRules, Vec<InvalidRule>)> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether some rules depend on the size of the files, which only the TOC knows
+    pub fn has_size_filter(&self) -> bool {
+        self.include.iter()
+            .chain(&self.exclude)
+            .flat_map(|rule| &rule.terms)
+            .any(|term| matches!(term, Term::Size(..)))
+    }
+
+    pub fn matches(&self, directory: &str, file_name: &str, size: u64) -> bool {
+        let directory = normalize_path(directory);
+        let path = if directory.is_empty() {
+            file_name.to_string()
+        } else {
+            format!("{directory}/{file_name}")
+        };
+
+        let included = self.include.is_empty()
+            || self.include.iter().any(|rule| rule.matches(&path, file_name, size));
+
+        included && !self.exclude.iter().any(|rule| rule.matches(&path, file_name, size))
+    }
+
+    pub fn matches_file(&self, file: &CpkFile) -> bool {
+        self.matches(
+            file.directory.as_deref().unwrap_or_default(),
+            &file.file_name,
+            file.extract_size as u64,
+        )
+    }
+
+    /// Whether a file may be selected when only its name is known, as in `cpk_list.cfg.bin`.
+    /// Terms on the path or the size are assumed to match, and exclusions are not applied.
+    pub fn may_match_name(&self, file_name: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|rule| rule.may_match_name(file_name))
+    }
+}
+
+fn has_extension(file_name: &str, extension: &str) -> bool {
+    let file_name = file_name.to_lowercase();
+    file_name.strip_suffix(extension)
+        .is_some_and(|stem| stem.ends_with('.'))
+}
+
+/// Sizes are given in bytes, or with a K, M or G suffix (powers of 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1024),
+        'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    let number: f64 = number.parse().ok()?;
+    (number >= 0.0).then_some((number * multiplier as f64) as u64)
+}
+
+/// Converts a glob to an anchored regex: `*` and `?` don't cross directories, `**` does
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    re.push('$');
+    re
+}
+
+/// The exact set of files to extract, keyed by directory and file name,
+/// along with the CPKs containing them
+#[derive(Debug, Default)]
+pub struct FileSelection {
+    files: HashMap<String, HashSet<String>>,
+    cpks: HashSet<String>,
+}
+
+impl FileSelection {
+    /// Selects the files from `cpk_list.cfg.bin`. Returns `None` if the list doesn't
+    /// know the directory of its files, or if the rules filter on sizes it doesn't have.
+    pub fn from_cpk_index(cpk_index: &CpkIndex, rules: &SelectionRules) -> Option<FileSelection> {
+        if !cpk_index.has_directories() || rules.has_size_filter() {
+            return None;
+        }
+
+        let mut selection = FileSelection::default();
+        for entry in cpk_index.entries() {
+            let directory = entry.directory.as_deref().unwrap_or_default();
+            if rules.matches(directory, &entry.file_name, 0) {
+                selection.insert(&normalize_path(&entry.cpk_name), directory, &entry.file_name);
+            }
+        }
+
+        Some(selection)
+    }
+
+    /// Selects the files from the TOC of every CPK
+    pub fn from_game_index(game_index: &GameIndex, rules: &SelectionRules) -> FileSelection {
+        let mut selection = FileSelection::default();
+        for found in game_index.files() {
+            let file = found.file;
+            if rules.matches(&file.directory, &file.file_name, file.extract_size as u64) {
+                selection.insert(&found.cpk.relative_path, &file.directory, &file.file_name);
+            }
+        }
+
+        selection
+    }
+
+    pub fn insert(&mut self, cpk_name: &str, directory: &str, file_name: &str) {
+        self.files.entry(normalize_path(directory))
+            .or_default()
+            .insert(file_name.to_string());
+
+        if !self.cpks.contains(cpk_name) {
+            self.cpks.insert(cpk_name.to_string());
+        }
+    }
+
+    pub fn contains(&self, directory: &str, file_name: &str) -> bool {
+        // Directories coming from the TOC are usually already normalized
+        let files = match self.files.get(directory) {
+            Some(files) => Some(files),
+            None => self.files.get(&normalize_path(directory)),
+        };
+
+        files.is_some_and(|files| files.contains(file_name))
+    }
+
+    pub fn contains_file(&self, file: &CpkFile) -> bool {
+        self.contains(file.directory.as_deref().unwrap_or_default(), &file.file_name)
+    }
+
+    /// Whether at least one selected file comes from the CPK at `cpk_path`, relative to the data folder.
+    /// `cpk_list.cfg.bin` may only give the name of the CPKs, which then matches every CPK of this name.
+    pub fn contains_cpk(&self, cpk_path: &str) -> bool {
+        let cpk_name = cpk_path.rsplit_once('/').map_or(cpk_path, |(_, name)| name);
+        self.cpks.contains(cpk_path) || self.cpks.contains(cpk_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.values().map(HashSet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_matches(glob: &str, path: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn glob_wildcards_stop_at_directories() {
+        assert!(glob_matches("*.png", "face.png"));
+        assert!(!glob_matches("*.png", "ui/face.png"));
+        assert!(glob_matches("ui/*.png", "ui/face.png"));
+        assert!(!glob_matches("ui/*.png", "ui/chara/face.png"));
+        assert!(glob_matches("face_?.png", "face_1.png"));
+        assert!(!glob_matches("face_?.png", "face_12.png"));
+    }
+
+    #[test]
+    fn glob_double_star_crosses_directories() {
+        assert!(glob_matches("data/**/face.png", "data/face.png"));
+        assert!(glob_matches("data/**/face.png", "data/ui/chara/face.png"));
+        assert!(glob_matches("data/**", "data/ui/face.png"));
+        assert!(!glob_matches("data/**/face.png", "common/face.png"));
+    }
+
+    #[test]
+    fn glob_escapes_regex_characters() {
+        assert!(glob_matches("chara_param.cfg.bin", "chara_param.cfg.bin"));
+        assert!(!glob_matches("chara_param.cfg.bin", "chara_param_cfg_bin"));
+        assert!(glob_matches("(1)+.bin", "(1)+.bin"));
+    }
+
+    #[test]
+    fn sizes_with_suffixes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("2K"), Some(2 * 1024));
+        assert_eq!(parse_size("1.5m"), Some(3 * 512 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size(" 4k "), Some(4 * 1024));
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("-1M"), None);
+        assert_eq!(parse_size("big"), None);
+    }
+
+    #[test]
+    fn exclusions_win_over_inclusions() {
+        let (rules, invalid) = SelectionRules::parse("ext:png\n!glob:ui/**\n");
+        assert!(invalid.is_empty());
+
+        assert!(rules.matches("chara", "face.png", 0));
+        assert!(!rules.matches("ui/chara", "face.png", 0));
+        assert!(!rules.matches("chara", "face.dds", 0));
+    }
+
+    #[test]
+    fn only_exclusions_select_everything_else() {
+        let (rules, _) = SelectionRules::parse("# skip the big files\n!size>1M\n");
+
+        assert!(rules.matches("data", "small.bin", 1024));
+        assert!(!rules.matches("data", "big.bin", 2 * 1024 * 1024));
+    }
+
+    #[test]
+    fn terms_of_a_rule_must_all_match() {
+        let (rules, _) = SelectionRules::parse("ext:bin size<1K\n");
+
+        assert!(rules.matches("data", "a.bin", 100));
+        assert!(!rules.matches("data", "a.bin", 4096));
+        assert!(!rules.matches("data", "a.png", 100));
+    }
+
+    #[test]
+    fn invalid_rules_are_reported_with_their_line() {
+        let (rules, invalid) = SelectionRules::parse("ext:png\nsize<lots\nre:(\n");
+
+        assert_eq!(invalid.iter().map(|rule| rule.line).collect::<Vec<_>>(), [2, 3]);
+        assert!(rules.matches("", "face.png", 0));
+    }
+}
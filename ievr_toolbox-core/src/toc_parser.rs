@@ -1,6 +1,6 @@
 use std::{collections::HashMap, sync::Arc};
 
-use crate::{CpkFile, utf_table::UTFTable};
+use crate::{CpkFile, cpk_info::{CpkHeader, TableLocation}, utf_table::UTFTable};
 
 mod column;
 
@@ -79,6 +79,32 @@ impl TocParser {
         Some((toc, content))
     }
 
+    pub(crate) fn header(&self, table: &UTFTable) -> CpkHeader {
+        let values = read_first_row(table);
+
+        let number = |name: &str| values.get(name)
+            .copied()
+            .filter(|value| *value >= 0)
+            .map(|value| value as u64);
+
+        let location = |offset: &str, size: &str| match (number(offset), number(size)) {
+            (Some(offset), Some(size)) if offset != 0 => Some(TableLocation { offset, size }),
+            _ => None,
+        };
+
+        CpkHeader {
+            version: number("Version"),
+            revision: number("Revision"),
+            alignment: number("Align"),
+            codec: number("Codec"),
+            content: location("ContentOffset", "ContentSize"),
+            toc: location("TocOffset", "TocSize"),
+            itoc: location("ItocOffset", "ItocSize"),
+            etoc: location("EtocOffset", "EtocSize"),
+            gtoc: location("GtocOffset", "GtocSize"),
+        }
+    }
+
     pub(crate) fn read(&mut self, table: &UTFTable, content_offset: u64) -> Vec<CpkFile> {
         const INVALID: usize = usize::MAX;
 
@@ -196,6 +222,51 @@ impl TocParser {
     }
 }
 
+/// Reads the numeric values of the first row of a table, keyed by column name.
+/// Non-numeric columns are read as -1.
+fn read_first_row(table: &UTFTable) -> HashMap<String, i64> {
+    let mut values = HashMap::new();
+
+    let string_pool = &table.data[table.metadata.string_pool_offset as usize..];
+    let mut col_ptr = table.metadata.first_column_pos() as usize;
+    let mut row_ptr = table.metadata.first_row_offset() as usize;
+
+    for _ in 0..table.metadata.column_count {
+        let column = ColumnDescriptor::new(table.data[col_ptr]);
+        let mut col_size = 1;
+        let mut name = None;
+
+        if column.has_name() {
+            let name_offset = column.string_offset(&table.data[col_ptr..]);
+            name = Some(read_utf_string(string_pool, name_offset as usize));
+
+            col_size += std::mem::size_of::<u32>();
+        }
+
+        let value = if column.is_row_storage() {
+            let value = column.read_number(&table.data[row_ptr..]);
+            row_ptr += column.value_len() as usize;
+            Some(value)
+        } else if column.has_default() {
+            Some(column.read_number(&table.data[col_ptr + col_size..]))
+        } else {
+            None
+        };
+
+        if column.has_default() {
+            col_size += column.value_len() as usize;
+        }
+
+        if let (Some(name), Some(value)) = (name, value) {
+            values.insert(name, value);
+        }
+
+        col_ptr += col_size;
+    }
+
+    values
+}
+
 /// Reads a string from a CRI UTF table string pool.
 ///
 /// `string_pool` = slice containing the string pool bytes  
@@ -12,28 +12,39 @@ pub const COLUMN_OFFSET: u32 = 0x20;
 pub struct UTFTable {
     pub data: Vec<u8>,
     pub metadata: Metadata,
+    pub masked: bool,
 }
 
 impl UTFTable {
     pub fn new(file: &DecryptedCpk, offset: usize) -> std::io::Result<UTFTable> {
         // Skip the CPK, TOC, ITOC... header and the unused fields
 
+        let out_of_bounds = || std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("The table at offset {offset:#x} goes past the end of the CPK"),
+        );
+
         // Read the 4-byte size field
-        let size_buf: [u8; 4] = file[8 + offset..12 + offset].try_into().unwrap();
+        let size_buf: [u8; 4] = file.get(8 + offset..12 + offset).ok_or_else(out_of_bounds)?.try_into().unwrap();
         let size = u32::from_le_bytes(size_buf) as usize;
 
-        // Read the entire table
-        let data = &file[16 + offset..16 + size + offset];
+        // Read the entire table, which at least has the header of its columns
+        let mut data = file.get(16 + offset..16 + size + offset).ok_or_else(out_of_bounds)?.to_vec();
+        if data.len() < COLUMN_OFFSET as usize {
+            return Err(out_of_bounds());
+        }
 
-        if is_utf_encrypted(&data) {
-            println!("Encrypted CPK")
+        let masked = is_utf_encrypted(&data);
+        if masked {
+            unmask_utf(&mut data);
         }
 
         let metadata = Metadata::new(&data);
 
         Ok(UTFTable {
-            data: data.to_vec(),
+            data,
             metadata,
+            masked,
         })
     }
 }
@@ -83,6 +94,15 @@ fn is_utf_encrypted(table: &[u8]) -> bool {
     magic == 0xF5F39E1F
 }
 
+/// Removes the XOR mask some CPKs apply on top of their UTF tables
+fn unmask_utf(table: &mut [u8]) {
+    let mut mask: u32 = 0x0000_655F;
+    for byte in table {
+        *byte ^= mask as u8;
+        mask = mask.wrapping_mul(0x0000_4115);
+    }
+}
+
 fn read_u16_be(data: &[u8], offset: usize) -> Result<u16, TryFromSliceError> {
     Ok(u16::from_be_bytes(data[offset..offset + 2].try_into()?))
 }
@@ -0,0 +1,94 @@
+use std::sync::{Condvar, Mutex, atomic::{AtomicUsize, Ordering}};
+
+use crossbeam::deque::{Steal, Stealer, Worker};
+
+/// Work shared by the threads of an executor. Each thread adds its work to its own queue and takes
+/// from it first, then steals from the queues of the other threads once its own is empty.
+///
+/// Threads with nothing to do wait for [`notify`](Self::notify), which is called when work is added
+/// and should be called whenever something else that can unblock them changes.
+pub(crate) struct WorkQueue<T> {
+    stealers: Vec<Stealer<T>>,
+    len: AtomicUsize,
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl<T> WorkQueue<T> {
+    /// The shared queue and the local queue of each of the `threads` threads
+    pub fn new(threads: usize) -> (Self, Vec<Worker<T>>) {
+        // Work is taken in the order it was added, by the owner of the queue as well as by thieves
+        let workers: Vec<Worker<T>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+
+        let queue = Self {
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            len: AtomicUsize::new(0),
+            generation: Mutex::new(0),
+            changed: Condvar::new(),
+        };
+        (queue, workers)
+    }
+
+    /// Number of items waiting in all the queues
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn push(&self, local: &Worker<T>, items: impl IntoIterator<Item = T>) {
+        let mut count = 0;
+        for item in items {
+            local.push(item);
+            count += 1;
+        }
+
+        if count > 0 {
+            self.len.fetch_add(count, Ordering::SeqCst);
+            self.notify();
+        }
+    }
+
+    /// Takes the next item of the local queue, or steals some from another thread
+    pub fn pop(&self, local: &Worker<T>) -> Option<T> {
+        let item = local.pop().or_else(|| self.steal(local))?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Some(item)
+    }
+
+    /// Moves half of the items of the first thread that has some to the local queue, and returns one of them
+    fn steal(&self, local: &Worker<T>) -> Option<T> {
+        loop {
+            let mut retry = false;
+            for stealer in &self.stealers {
+                match stealer.steal_batch_and_pop(local) {
+                    Steal::Success(item) => return Some(item),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => {}
+                }
+            }
+
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    /// A value that changes on every [`notify`](Self::notify), to pass to [`wait`](Self::wait)
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Wakes up the threads waiting for work
+    pub fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    /// Blocks until [`notify`](Self::notify) is called, unless it was called since `generation` was read.
+    /// Reading the generation before looking for work means that no change is missed.
+    pub fn wait(&self, generation: u64) {
+        let mut current = self.generation.lock().unwrap();
+        while *current == generation {
+            current = self.changed.wait(current).unwrap();
+        }
+    }
+}